@@ -3,8 +3,7 @@
 #![feature(lang_items)]
 #![feature(global_asm)]
 
-use utils::x86;
-mod elf;
+use utils::{elf, x86};
 
 const SECTOR_SIZE: usize = 512;
 type Sector = [u8; SECTOR_SIZE];
@@ -61,16 +60,18 @@ fn boot_main() {
 
         // Load each program segment (ignores ph flags).
         for ph in (*elf).prog_headers() {
-            let pa = ph.p_paddr;
-            read_segment(pa, ph.p_filesz, ph.p_offset);
+            let pa = ph.p_paddr as *mut u8;
+            let filesz = ph.p_filesz as usize;
+            read_segment(pa, filesz, ph.p_offset as usize);
             if ph.p_memsz > ph.p_filesz {
                 // fill with zero
-                x86::stosb(pa.add(ph.p_filesz), 0, ph.p_memsz - ph.p_filesz);
+                x86::stosb(pa.add(filesz), 0, ph.p_memsz as usize - filesz);
             }
         }
 
         // Go to kernel
-        ((*elf).e_entry)();
+        let entry: extern "C" fn() = core::mem::transmute((*elf).e_entry as usize);
+        entry();
     }
 }
 
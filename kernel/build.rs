@@ -2,10 +2,12 @@
 fn generate_vector_asm() {
     let mut asm_string = ".globl alltraps\n".to_owned();
     for i in 0..256 {
-        asm_string += &format!(
-            ".globl vector0\nvector{}:\n  pushl $0\n  pushl ${}\n  jmp alltraps\n",
-            i, i
-        );
+        asm_string += &format!(".globl vector{}\nvector{}:\n", i, i);
+        // The processor pushes an error code for these exceptions by itself.
+        if !(i == 8 || (10..=14).contains(&i) || i == 17) {
+            asm_string += "  pushl $0\n";
+        }
+        asm_string += &format!("  pushl ${}\n  jmp alltraps\n", i);
     }
     asm_string += ".data\n.globl VECTORS\nVECTORS:\n";
     for i in 0..256 {
@@ -27,8 +29,27 @@ fn copy_init() {
     println!("cargo:rerun-if-changed={}", init_bin.to_str().unwrap());
 }
 
+// Generates tick_hz.rs for lapic.rs from $TICK_HZ, 100 if unset.
+fn generate_tick_hz() {
+    let hz = match std::env::var("TICK_HZ") {
+        Ok(s) => s.parse::<u32>().expect("TICK_HZ must be a number"),
+        Err(_) => 100,
+    };
+    assert!(
+        (1..=1000).contains(&hz),
+        "TICK_HZ must be between 1 and 1000"
+    );
+
+    let out_dir = std::env::var_os("OUT_DIR").unwrap();
+    let dest_path = std::path::Path::new(&out_dir).join("tick_hz.rs");
+    std::fs::write(&dest_path, format!("{}\n", hz)).unwrap();
+
+    println!("cargo:rerun-if-env-changed=TICK_HZ");
+}
+
 fn main() {
     generate_vector_asm();
+    generate_tick_hz();
     copy_init();
     println!("cargo:rerun-if-changed=build.rs");
 }
@@ -1,11 +1,127 @@
 use super::fs::file::{init_dev, Dev, CONSOLE};
-use super::fs::Result;
+use super::fs::{Error, Result};
+use super::lock::spin::SpinMutex;
+use super::proc;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+
+/// Control-x
+const fn ctrl(c: u8) -> u8 {
+    c - b'@'
+}
+const CTRL_D: u8 = ctrl(b'D');
+const CTRL_U: u8 = ctrl(b'U');
+
+const INPUT_BUF: usize = 128;
+
+/// Console input ring buffer
+struct Input {
+    buf: [u8; INPUT_BUF],
+    /// Read index
+    r: usize,
+    /// Write index: input up to here is visible to console_read()
+    w: usize,
+    /// Edit index: the line being typed is between w and e
+    e: usize,
+}
+impl Input {
+    /// Is there no input for console_read()?
+    fn is_empty(&self) -> bool {
+        self.r == self.w
+    }
+}
+
+static INPUT: SpinMutex<Input> = SpinMutex::new(
+    "input",
+    Input {
+        buf: [0; INPUT_BUF],
+        r: 0,
+        w: 0,
+        e: 0,
+    },
+);
+
+fn input_chan() -> usize {
+    &INPUT as *const _ as usize
+}
+
+/// Console input handler. The keyboard and serial port drivers
+/// call this with each character they receive.
+/// Does erase/kill processing, echoes the character,
+/// and wakes up console_read() once a whole line has arrived.
+pub fn intr(c: u8) {
+    let mut input = INPUT.lock();
+    match c {
+        0 => {}
+        CTRL_U => {
+            // Kill line.
+            while input.e != input.w && input.buf[(input.e - 1) % INPUT_BUF] != b'\n' {
+                input.e -= 1;
+                vga::putc(BACKSPACE);
+            }
+        }
+        BACKSPACE | DELETE => {
+            if input.e != input.w {
+                input.e -= 1;
+                vga::putc(BACKSPACE);
+            }
+        }
+        c => {
+            if input.e - input.r < INPUT_BUF {
+                let c = if c == b'\r' { b'\n' } else { c };
+                let e = input.e;
+                input.buf[e % INPUT_BUF] = c;
+                input.e += 1;
+                if c != CTRL_D {
+                    vga::putc(c);
+                }
+                if c == b'\n' || c == CTRL_D || input.e == input.r + INPUT_BUF {
+                    input.w = input.e;
+                    proc::wakeup(input_chan());
+                }
+            }
+        }
+    }
+}
 
 pub fn console_write(buf: &[u8]) -> Result<usize> {
-    todo!()
+    vga::write_bytes(buf);
+    Ok(buf.len())
 }
+
+/// Read a line from the console, blocking until one is available.
+/// Returns 0 at end of file (^D at the beginning of a line).
 pub fn console_read(buf: &mut [u8]) -> Result<usize> {
-    todo!()
+    let mut input = INPUT.lock();
+    let mut n = 0;
+    while n < buf.len() {
+        // wait until intr() has put some input into the buffer.
+        while input.is_empty() {
+            if proc::killed() {
+                return Err(Error::Interrupted);
+            }
+            proc::sleep(input_chan(), &input);
+        }
+        let c = input.buf[input.r % INPUT_BUF];
+        input.r += 1;
+        if c == CTRL_D {
+            // end-of-file
+            if n > 0 {
+                // Save ^D for next time, to make sure
+                // caller gets a 0-byte result.
+                input.r -= 1;
+            }
+            break;
+        }
+        buf[n] = c;
+        n += 1;
+        if c == b'\n' {
+            // a whole line has arrived, return to the user-level read().
+            break;
+        }
+    }
+    Ok(n)
 }
 
 pub fn init() {
@@ -14,7 +130,7 @@ pub fn init() {
         write: Some(console_write),
     };
     unsafe { init_dev(CONSOLE, cons) };
-    super::ioapic::enable(super::trap::IRQ_KBD, 0);
+    super::kbd::init();
 }
 
 pub mod vga {
@@ -132,6 +248,7 @@ pub mod vga {
         pub fn write_byte(&mut self, byte: u8) {
             match byte {
                 b'\n' => self.new_line(),
+                super::BACKSPACE => self.backspace(),
                 byte => {
                     if self.column_position >= WIDTH {
                         self.new_line();
@@ -159,6 +276,43 @@ pub mod vga {
             }
         }
 
+        /// Erase the character before the cursor.
+        fn backspace(&mut self) {
+            if self.column_position > 0 {
+                self.column_position -= 1;
+            } else if self.row_position > 0 {
+                self.row_position -= 1;
+                self.column_position = WIDTH - 1;
+            } else {
+                return;
+            }
+            self.write_cell(
+                self.row_position,
+                self.column_position,
+                ScreenCell {
+                    ascii: b' ',
+                    color: self.color,
+                },
+            );
+            self.update_cursor();
+        }
+
+        /// Write a byte to both the screen and the serial port.
+        fn put(&mut self, byte: u8) {
+            match byte {
+                super::BACKSPACE => {
+                    uart::putc(super::BACKSPACE);
+                    uart::putc(b' ');
+                    uart::putc(super::BACKSPACE);
+                }
+                byte => uart::putc(byte),
+            }
+            match byte {
+                0x20..=0x7E | b'\n' | super::BACKSPACE => self.write_byte(byte),
+                _ => self.write_byte(0xFE),
+            }
+        }
+
         pub fn clear_screen(&mut self) {
             for r in 0..HEIGHT {
                 self.clear_row(r);
@@ -216,6 +370,20 @@ pub mod vga {
         }
     }
 
+    /// Write a byte to the screen and the serial port.
+    /// BACKSPACE erases the previous character.
+    pub fn putc(byte: u8) {
+        VGA_WRITER.lock().put(byte);
+    }
+
+    /// Write raw bytes to the screen and the serial port.
+    pub fn write_bytes(bytes: &[u8]) {
+        let mut writer = VGA_WRITER.lock();
+        for &byte in bytes {
+            writer.put(byte);
+        }
+    }
+
     #[doc(hidden)]
     pub fn _print(args: core::fmt::Arguments) {
         let mut writer = VGA_WRITER.lock();
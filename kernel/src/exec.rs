@@ -0,0 +1,184 @@
+//! Loading ELF executables into the current process.
+
+use crate::fs::inode::{self, FileType, InodeGuard};
+use crate::fs::log;
+use crate::memory::pg_dir::{ent_flag, PageDirectory};
+use crate::memory::PAGE_SIZE;
+use crate::proc::my_proc;
+use crate::syscall::{Result, SyscallError};
+use crate::vm;
+use crate::vma::{self, Vma, VmaKind};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use utils::elf::{self, ElfHeader, ProgHeader};
+
+/// Max exec arguments
+pub const MAX_ARG: usize = 32;
+
+/// Read a plain-old-data struct at offset off of ip.
+fn read_struct<T>(ip: &InodeGuard, off: usize) -> Option<T> {
+    let mut val = core::mem::MaybeUninit::<T>::zeroed();
+    let dst =
+        unsafe { core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    match ip.read(dst, off) {
+        Ok(n) if n == size_of::<T>() => Some(unsafe { val.assume_init() }),
+        _ => None,
+    }
+}
+
+fn round_up(x: usize) -> usize {
+    (x + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
+
+/// Load the program segments of the ELF file ip into pg_dir,
+/// adding a text or data area to vmas for each of them.
+/// Returns the entry point and the size of the loaded image.
+fn load_segments(
+    pg_dir: &mut PageDirectory,
+    vmas: &mut Vec<Vma>,
+    ip: &InodeGuard,
+) -> Result<(usize, usize)> {
+    let elf: ElfHeader = read_struct(ip, 0).ok_or(SyscallError::NoExec)?;
+    if !elf.verify() {
+        return Err(SyscallError::NoExec);
+    }
+
+    let mut size = 0;
+    for i in 0..elf.e_phnum as usize {
+        let off = elf.e_phoff as usize + i * size_of::<ProgHeader>();
+        let ph: ProgHeader = read_struct(ip, off).ok_or(SyscallError::NoExec)?;
+        if ph.p_type != elf::ELF_PROG_LOAD {
+            continue;
+        }
+        let (vaddr, memsz, filesz) = (
+            ph.p_vaddr as usize,
+            ph.p_memsz as usize,
+            ph.p_filesz as usize,
+        );
+        // Segments must come in ascending order of address.
+        if memsz < filesz || vaddr < size {
+            return Err(SyscallError::NoExec);
+        }
+        let end = vaddr.checked_add(memsz).ok_or(SyscallError::NoExec)?;
+        if end > vma::HEAP_TOP {
+            return Err(SyscallError::NoExec);
+        }
+        let (kind, prot, perm) = if ph.p_flags & elf::ELF_PROG_FLAG_WRITE != 0 {
+            let prot = vma::PROT_READ | vma::PROT_WRITE;
+            (VmaKind::Data, prot, ent_flag::WRITABLE)
+        } else {
+            (VmaKind::Text, vma::PROT_READ | vma::PROT_EXEC, 0)
+        };
+        // Pages already allocated for the previous segments keep their area.
+        // A segment that starts inside the last of them shares that page, so
+        // the page gets the permissions of both segments.
+        let start = round_up(size);
+        let first = vaddr - vaddr % PAGE_SIZE;
+        if first < start {
+            let shared = vmas.iter().find(|v| v.start <= first && first < v.end);
+            let shared = shared.map_or(0, |v| v.prot) | prot;
+            vma::protect(pg_dir, vmas, first, start, shared).map_err(|_| SyscallError::NoExec)?;
+        }
+        size = vm::uvm::alloc(pg_dir, size, end, perm).ok_or(SyscallError::NoMemory)?;
+        if start < round_up(size) {
+            vmas.push(Vma::anonymous(kind, start, round_up(size), prot));
+        }
+        vm::uvm::load(pg_dir, vaddr, ip, ph.p_offset as usize, filesz).ok_or(SyscallError::Io)?;
+    }
+    Ok((elf.e_entry as usize, size))
+}
+
+/// Build the initial user stack beneath sp in pg_dir.
+/// Returns the initial stack pointer.
+fn push_args(pg_dir: &mut PageDirectory, mut sp: usize, argv: &[&[u8]]) -> Result<usize> {
+    // Arguments that don't fit in the first stack page are rejected.
+    let too_big = SyscallError::TooBig;
+
+    // Push argument strings, prepare rest of stack in ustack.
+    let mut ustack = [0u32; 3 + MAX_ARG + 1];
+    for (i, arg) in argv.iter().enumerate() {
+        sp = sp.checked_sub(arg.len() + 1).ok_or(too_big)? & !3;
+        vm::uvm::copy_out(pg_dir, sp, arg).ok_or(too_big)?;
+        vm::uvm::copy_out(pg_dir, sp + arg.len(), b"\0").ok_or(too_big)?;
+        ustack[3 + i] = sp as u32;
+    }
+    ustack[3 + argv.len()] = 0;
+
+    ustack[0] = 0xFFFF_FFFF; // fake return PC
+    ustack[1] = argv.len() as u32;
+    ustack[2] = (sp.wrapping_sub((argv.len() + 1) * 4)) as u32; // argv pointer
+
+    let ustack = &ustack[..3 + argv.len() + 1];
+    let len = ustack.len() * size_of::<u32>();
+    sp = sp.checked_sub(len).ok_or(too_big)?;
+    let bytes = unsafe { core::slice::from_raw_parts(ustack.as_ptr() as *const u8, len) };
+    vm::uvm::copy_out(pg_dir, sp, bytes).ok_or(too_big)?;
+    Ok(sp)
+}
+
+/// Replace the image of the current process with the program at path.
+/// argv is copied onto the new user stack, so it may point into the old image.
+pub fn exec(path: &str, argv: &[&[u8]]) -> Result<u32> {
+    if argv.len() > MAX_ARG {
+        return Err(SyscallError::TooBig);
+    }
+
+    let tx = log::begin_op();
+    let ip = inode::from_name(path).ok_or(SyscallError::NoEntry)?;
+    let mut pg_dir = vm::setup_kvm().ok_or(SyscallError::NoMemory)?;
+    let mut vmas = Vec::new();
+
+    let loaded = {
+        let ip = ip.lock();
+        if ip.type_ == FileType::File {
+            load_segments(&mut pg_dir, &mut vmas, &ip)
+        } else {
+            Err(SyscallError::PermissionDenied)
+        }
+    };
+    drop(ip);
+    drop(tx);
+
+    let image = loaded.and_then(|(entry, size)| {
+        // The heap starts at the next page boundary, empty.
+        let size = round_up(size);
+        let rw = vma::PROT_READ | vma::PROT_WRITE;
+        vmas.push(Vma::anonymous(VmaKind::Heap, size, size, rw));
+        let sp = vma::setup_stack(&mut pg_dir, &mut vmas).ok_or(SyscallError::NoMemory)?;
+        let sp = push_args(&mut pg_dir, sp, argv)?;
+        Ok((entry, size, sp))
+    });
+    let (entry, size, sp) = match image {
+        Ok(image) => image,
+        Err(err) => {
+            vm::free_vm(pg_dir);
+            return Err(err);
+        }
+    };
+
+    // Commit to the user image.
+    let p = my_proc();
+    let (mut old_pg_dir, mut old_vmas): (Box<PageDirectory>, _) = {
+        let mut p = p.lock();
+
+        // Save program name for debugging.
+        let name = path.rsplit('/').next().unwrap_or(path).as_bytes();
+        let len = usize::min(name.len(), p.name.len() - 1);
+        p.name = [0; 16];
+        p.name[..len].copy_from_slice(&name[..len]);
+
+        p.size = size;
+        unsafe {
+            (*p.trap_frame).eip = entry; // main
+            (*p.trap_frame).esp = sp;
+        }
+        let old_vmas = core::mem::replace(&mut p.vmas, vmas);
+        (core::mem::replace(&mut p.pg_dir, pg_dir), old_vmas)
+    };
+    vm::uvm::switch(&p);
+    let removed = vma::unmap(&mut old_pg_dir, &mut old_vmas, 0, vma::MMAP_TOP);
+    vma::release(removed);
+    vm::free_vm(old_pg_dir);
+    Ok(0)
+}
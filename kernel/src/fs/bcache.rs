@@ -1,11 +1,12 @@
 use super::ide;
-use super::BLK_SIZE;
 use crate::lock::sleep::{SleepMutex, SleepMutexGuard};
 use crate::lock::spin::SpinMutex;
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
+use core::mem::ManuallyDrop;
 use core::sync::atomic::{AtomicU8, Ordering};
 use lazy_static::lazy_static;
+use utils::fs::BLK_SIZE;
 
 /// buffer has been read from disk
 const B_VALID: u8 = 0x2;
@@ -42,7 +43,7 @@ impl Flags {
 }
 
 pub struct BufLocked {
-    guard: SleepMutexGuard<'static, Buf>,
+    guard: ManuallyDrop<SleepMutexGuard<'static, Buf>>,
 }
 impl core::ops::Deref for BufLocked {
     type Target = Buf;
@@ -57,7 +58,10 @@ impl core::ops::DerefMut for BufLocked {
 }
 impl Drop for BufLocked {
     fn drop(&mut self) {
-        unsafe { BCACHE.lock().put(self.dev, self.block_no) };
+        let (dev, block_no) = (self.dev, self.block_no);
+        // Release the sleep lock before the buffer can be freed by put().
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        unsafe { BCACHE.lock().put(dev, block_no) };
     }
 }
 
@@ -79,18 +83,13 @@ impl Buf {
     pub fn id(&self) -> usize {
         self as *const _ as usize
     }
+    /// Write the contents to disk.
     pub fn write(&self) {
-        if self.flags.dirty() {
-            ide::write_to_disk(self);
-        }
+        self.flags.set_dirty(true);
+        ide::write_to_disk(self);
         debug_assert!(!self.flags.dirty());
     }
 }
-impl Drop for Buf {
-    fn drop(&mut self) {
-        log!("buf drop");
-    }
-}
 
 struct Bcache {
     cache: BTreeMap<(u32, u32), (usize, &'static SleepMutex<Buf>)>,
@@ -127,14 +126,21 @@ impl Bcache {
             Some((ref_cnt, mtx)) => {
                 *ref_cnt -= 1;
                 if *ref_cnt == 0 {
+                    let mtx = *mtx as *const SleepMutex<Buf> as *mut SleepMutex<Buf>;
+                    self.cache.remove(&key);
                     // Retrieve the box and drop it.
                     drop(Box::from_raw(mtx));
-                    self.cache.remove(&key);
                 }
             }
             None => panic!("Bcache::put: no entry"),
         }
     }
+    fn pin(&mut self, dev: u32, block_no: u32) {
+        match self.cache.get_mut(&(dev, block_no)) {
+            Some((ref_cnt, _)) => *ref_cnt += 1,
+            None => panic!("Bcache::pin: no entry"),
+        }
+    }
 }
 
 lazy_static! {
@@ -143,7 +149,9 @@ lazy_static! {
 
 pub fn read(dev: u32, block_no: u32) -> BufLocked {
     let b = BCACHE.lock().get(dev, block_no);
-    let mut b = BufLocked { guard: b.lock() };
+    let mut b = BufLocked {
+        guard: ManuallyDrop::new(b.lock()),
+    };
     if !b.flags.valid() {
         ide::read_from_disk(&mut b);
     }
@@ -151,6 +159,16 @@ pub fn read(dev: u32, block_no: u32) -> BufLocked {
     b
 }
 
+/// Keep b in the cache even after it is released,
+/// so that the log can write it back later.
+pub fn pin(b: &Buf) {
+    BCACHE.lock().pin(b.dev, b.block_no);
+}
+/// Undo pin(). The buffer may be evicted once it is released.
+pub fn unpin(b: &Buf) {
+    unsafe { BCACHE.lock().put(b.dev, b.block_no) };
+}
+
 pub fn init() {
     lazy_static::initialize(&BCACHE);
 }
@@ -0,0 +1,205 @@
+//! File descriptors
+
+use super::inode::{InodeRef, Stat};
+use super::log;
+use super::pipe::PipeRef;
+use super::{Error, Result};
+use alloc::sync::Arc;
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use utils::fs::{BLK_SIZE, MAX_OP_BLOCKS};
+
+pub const N_DEV: usize = 10;
+
+pub type DevRead = fn(&mut [u8]) -> super::Result<usize>;
+pub type DevWrite = fn(&[u8]) -> super::Result<usize>;
+
+/// table mapping major device number to device functions
+pub struct Dev {
+    pub read: Option<DevRead>,
+    pub write: Option<DevWrite>,
+}
+static mut DEV: [Dev; N_DEV] = [Dev {
+    read: None,
+    write: None,
+}; N_DEV];
+
+/// Safe wrapper for DEV
+pub fn dev() -> &'static [Dev] {
+    unsafe { &DEV[..] }
+}
+
+pub unsafe fn init_dev(dev_num: u32, dev: Dev) {
+    DEV[dev_num as usize] = dev;
+}
+
+pub const CONSOLE: u32 = 1;
+
+/// Write a few blocks at a time to avoid exceeding
+/// the maximum log transaction size, including
+/// i-node, indirect block, allocation blocks,
+/// and 2 blocks of slop for non-aligned writes.
+/// This really belongs lower down, since InodeGuard::write()
+/// might be writing a device like the console.
+const MAX_WRITE: usize = ((MAX_OP_BLOCKS - 1 - 1 - 2) / 2) * BLK_SIZE;
+
+/// What a file refers to
+pub enum FileKind {
+    Inode(InodeRef),
+    Device { ip: InodeRef, major: u16 },
+    Pipe(PipeRef),
+}
+
+/// Open file
+pub struct File {
+    pub readable: bool,
+    pub writable: bool,
+    kind: ManuallyDrop<FileKind>,
+    /// Offset for inode files. Only changed while the inode is locked.
+    off: AtomicUsize,
+}
+pub type FileRef = Arc<File>;
+
+impl File {
+    pub fn new(kind: FileKind, readable: bool, writable: bool) -> FileRef {
+        Arc::new(Self {
+            readable,
+            writable,
+            kind: ManuallyDrop::new(kind),
+            off: AtomicUsize::new(0),
+        })
+    }
+
+    /// Get metadata about file.
+    pub fn stat(&self) -> Result<Stat> {
+        match &*self.kind {
+            FileKind::Inode(ip) | FileKind::Device { ip, .. } => Ok(ip.lock().stat()),
+            FileKind::Pipe(_) => Err(Error::InvalidArg("pipe")),
+        }
+    }
+
+    /// Read from file.
+    pub fn read(&self, dst: &mut [u8]) -> Result<usize> {
+        if !self.readable {
+            return Err(Error::InvalidArg("not readable"));
+        }
+        match &*self.kind {
+            FileKind::Inode(ip) => {
+                let ip = ip.lock();
+                let off = self.off.load(Ordering::SeqCst);
+                let n = ip.read(dst, off)?;
+                self.off.store(off + n, Ordering::SeqCst);
+                Ok(n)
+            }
+            FileKind::Device { major, .. } => {
+                let read = dev()
+                    .get(*major as usize)
+                    .and_then(|d| d.read)
+                    .ok_or(Error::InvalidArg("no such device"))?;
+                read(dst)
+            }
+            FileKind::Pipe(pipe) => pipe.read(dst),
+        }
+    }
+
+    /// Write to file.
+    pub fn write(&self, src: &[u8]) -> Result<usize> {
+        if !self.writable {
+            return Err(Error::InvalidArg("not writable"));
+        }
+        match &*self.kind {
+            FileKind::Inode(ip) => {
+                let mut tot = 0;
+                while tot < src.len() {
+                    let n = usize::min(src.len() - tot, MAX_WRITE);
+                    let _tx = log::begin_op();
+                    let mut ip = ip.lock();
+                    let off = self.off.load(Ordering::SeqCst);
+                    let r = match ip.write(&src[tot..tot + n], off) {
+                        Ok(r) => r,
+                        Err(e) if tot == 0 => return Err(e),
+                        Err(_) => break,
+                    };
+                    self.off.store(off + r, Ordering::SeqCst);
+                    tot += r;
+                    if r != n {
+                        // error from InodeGuard::write
+                        break;
+                    }
+                }
+                Ok(tot)
+            }
+            FileKind::Device { major, .. } => {
+                let write = dev()
+                    .get(*major as usize)
+                    .and_then(|d| d.write)
+                    .ok_or(Error::InvalidArg("no such device"))?;
+                write(src)
+            }
+            FileKind::Pipe(pipe) => pipe.write(src),
+        }
+    }
+}
+
+impl File {
+    /// Read from an inode file at off, without using or moving the file offset.
+    pub fn read_at(&self, dst: &mut [u8], off: usize) -> Result<usize> {
+        match &*self.kind {
+            FileKind::Inode(ip) => ip.lock().read(dst, off),
+            _ => Err(Error::InvalidArg("not an inode")),
+        }
+    }
+
+    /// Write to an inode file at off, without using or moving the file offset.
+    /// The part of src past the end of the file is not written.
+    pub fn write_at(&self, src: &[u8], off: usize) -> Result<usize> {
+        let ip = match &*self.kind {
+            FileKind::Inode(ip) => ip,
+            _ => return Err(Error::InvalidArg("not an inode")),
+        };
+        let mut tot = 0;
+        while tot < src.len() {
+            let _tx = log::begin_op();
+            let mut ip = ip.lock();
+            let pos = off + tot;
+            if pos >= ip.size {
+                break;
+            }
+            let n = usize::min(usize::min(src.len() - tot, MAX_WRITE), ip.size - pos);
+            let r = ip.write(&src[tot..tot + n], pos)?;
+            tot += r;
+            if r != n {
+                break;
+            }
+        }
+        Ok(tot)
+    }
+
+    /// The inode of an inode file.
+    pub fn inode(&self) -> Option<&InodeRef> {
+        match &*self.kind {
+            FileKind::Inode(ip) => Some(ip),
+            _ => None,
+        }
+    }
+
+    /// Whether the file refers to an inode that is not a device.
+    pub fn is_inode(&self) -> bool {
+        matches!(&*self.kind, FileKind::Inode(_))
+    }
+}
+
+impl Drop for File {
+    /// Close file. Releasing the inode may free it on disk,
+    /// so it happens inside a transaction.
+    fn drop(&mut self) {
+        let kind = unsafe { ManuallyDrop::take(&mut self.kind) };
+        match kind {
+            FileKind::Pipe(pipe) => pipe.close(self.writable),
+            kind => {
+                let _tx = log::begin_op();
+                drop(kind);
+            }
+        }
+    }
+}
@@ -1,11 +1,11 @@
 use super::bcache::{Buf, Flags};
-use super::BLK_SIZE;
 use crate::ioapic;
 use crate::lock::spin::SpinMutex;
 use crate::proc;
 use crate::trap;
 use alloc::collections::VecDeque;
 use lazy_static::lazy_static;
+use utils::fs::BLK_SIZE;
 use utils::x86;
 
 const SECTOR_SIZE: usize = 512;
@@ -19,6 +19,7 @@ const IDE_CMD_READ: u8 = 0x20;
 const IDE_CMD_WRITE: u8 = 0x30;
 const IDE_CMD_RDMUL: u8 = 0xC4;
 const IDE_CMD_WRMUL: u8 = 0xC5;
+const IDE_CMD_IDENTIFY: u8 = 0xEC;
 
 const PORT_BASE: u16 = 0x1F0;
 
@@ -32,6 +33,14 @@ fn have_disk1() -> bool {
     unsafe { HAVE_DISK1 }
 }
 
+/// Number of blocks on each disk, as reported by IDENTIFY DEVICE.
+static mut CAPACITY: [u32; 2] = [0; 2];
+
+/// Return the number of blocks on dev.
+pub fn capacity(dev: u32) -> u32 {
+    unsafe { CAPACITY[dev as usize & 1] }
+}
+
 lazy_static! {
     static ref IDE_QUEUE: SpinMutex<IdeQueue> = SpinMutex::new("IDE_QUE", IdeQueue::new());
 }
@@ -104,7 +113,7 @@ impl IdeQueue {
         );
         match req.cmd {
             Command::Read => {
-                x86::outb(0x1F0, read_cmd);
+                x86::outb(0x1F7, read_cmd);
             }
             Command::Write => {
                 x86::outb(0x1F7, write_cmd);
@@ -154,6 +163,7 @@ fn wait(check_err: bool) -> Option<()> {
 
 pub fn init() {
     let last_cpu = proc::cpus().len() - 1;
+    unsafe { trap::register_handler(trap::T_IRQ0 + trap::IRQ_IDE, ide_intr) };
     ioapic::enable(trap::IRQ_IDE, last_cpu);
     wait(false);
 
@@ -167,10 +177,27 @@ pub fn init() {
     }
     dbg!(have_disk1());
 
+    let sector_per_block = (BLK_SIZE / SECTOR_SIZE) as u32;
+    for dev in 0..if have_disk1() { 2 } else { 1 } {
+        let sectors = identify(dev).expect("ide: identify");
+        unsafe { CAPACITY[dev as usize] = sectors / sector_per_block };
+    }
+
     // Switch back to disk 0
     x86::outb(PORT_BASE + 6, 0xE0 | (0 << 4));
 }
 
+/// Ask dev for its identification and return its number of addressable sectors.
+fn identify(dev: u32) -> Option<u32> {
+    let mut id = [0u32; SECTOR_SIZE / 4];
+    x86::outb(PORT_BASE + 6, 0xE0 | (((dev & 1) << 4) as u8));
+    x86::outb(PORT_BASE + 7, IDE_CMD_IDENTIFY);
+    wait(true)?;
+    x86::insl(PORT_BASE, id.as_mut_ptr(), SECTOR_SIZE / 4);
+    // Words 60 and 61 hold the total number of LBA28 sectors.
+    Some(id[30])
+}
+
 pub fn read_from_disk(b: &mut Buf) {
     assert!(!b.flags.valid(), "read_from_disk: nothing to do");
     if b.dev != 0 {
@@ -194,14 +221,14 @@ pub fn read_from_disk(b: &mut Buf) {
     }
 }
 pub fn write_to_disk(b: &Buf) {
-    assert!(b.flags.dirty(), "read_from_disk: nothing to do");
+    assert!(b.flags.dirty(), "write_to_disk: nothing to do");
     if b.dev != 0 {
-        assert!(have_disk1(), "read_from_disk: ide disk 1 not present");
+        assert!(have_disk1(), "write_to_disk: ide disk 1 not present");
     }
 
     let mut ide_que = IDE_QUEUE.lock();
     let req = Request {
-        cmd: Command::Read,
+        cmd: Command::Write,
         dev: b.dev,
         block_no: b.block_no,
         data: b.data.as_ptr() as *const _ as *mut _,
@@ -217,8 +244,7 @@ pub fn write_to_disk(b: &Buf) {
 }
 
 /// Interrupt handler.
-#[no_mangle]
-pub extern "C" fn ide_intr() {
+fn ide_intr(_: &mut trap::TrapFrame) {
     let mut ide_que = IDE_QUEUE.lock();
     if !ide_que.is_empty() {
         ide_que.notify_ready();
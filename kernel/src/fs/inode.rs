@@ -1,17 +1,18 @@
 use super::bcache;
 use super::file;
-use super::free_disk_block;
-use super::DirEnt;
+use super::log;
+use super::ROOT_DEV;
+use super::{alloc_disk_block, free_disk_block};
 use super::{Error, Result};
-use crate::lock::sleep::SleepMutex;
+use crate::lock::sleep::{SleepMutex, SleepMutexGuard};
 use crate::lock::spin::SpinMutex;
 use crate::proc::my_proc;
 use alloc::collections::BTreeMap;
 use alloc::sync::{Arc, Weak};
 use lazy_static::lazy_static;
+use utils::fs::{DirEnt, OnDiskInode, BLK_SIZE, INODES_PER_BLOCK, MAX_FILE, N_DIRECT, N_INDIRECT};
 
-const ROOT_DEV: u32 = 1;
-const ROOT_INO: u32 = 1;
+const ROOT_INO: u32 = utils::fs::ROOT_INO as u32;
 
 pub type InodeRef = Arc<Inode>;
 
@@ -30,48 +31,50 @@ impl Inode {
         }
     }
 
-    /// Truncate inode (discard contents).
-    /// Only called when the inode has no links to it
-    /// (no directory entries referring to it)
-    /// and has no in-memory reference to it
-    /// (is not an open file or current directory).
-    fn trunc(&self) {
-        use super::{N_DIRECT, N_INDIRECT};
+    /// Lock the inode without reading it from disk.
+    fn lock_loaded(&self) -> InodeGuard<'_> {
+        InodeGuard {
+            inode: self,
+            body: self.body.lock(),
+        }
+    }
 
+    /// Lock the inode.
+    /// Reads the inode from disk if necessary.
+    pub fn lock(&self) -> InodeGuard<'_> {
         let mut body = self.body.lock();
-        for addr in body.addrs[..N_DIRECT].iter_mut() {
-            if *addr != 0 {
-                free_disk_block(self.dev, *addr);
-                *addr = 0;
+        if !body.valid {
+            let b = bcache::read(self.dev, super::super_block().inode_block(self.inum));
+            let dip = unsafe {
+                let dinodes = b.data.as_ptr() as *const OnDiskInode;
+                &*dinodes.add(self.inum as usize % INODES_PER_BLOCK)
+            };
+            body.type_ = FileType::from_u16(u16::from_le(dip.type_)).expect("ilock: bad type");
+            body.major = u16::from_le(dip.major);
+            body.minor = u16::from_le(dip.minor);
+            body.nlink = u16::from_le(dip.n_link);
+            body.size = u32::from_le(dip.size) as usize;
+            for (dst, src) in body.addrs.iter_mut().zip(dip.addrs.iter()) {
+                *dst = u32::from_le(*src);
             }
-        }
-        let indirect = body.addrs[N_DIRECT];
-        if indirect != 0 {
-            let b = bcache::read(self.dev, indirect);
-            {
-                let slots = unsafe { *(b.data.as_ptr() as *const _ as *const [u32; N_INDIRECT]) };
-                for addr in slots.iter() {
-                    if *addr != 0 {
-                        free_disk_block(self.dev, *addr);
-                    }
-                }
-                free_disk_block(self.dev, indirect);
+            drop(b);
+            body.valid = true;
+            if body.type_ == FileType::Invalid {
+                panic!("ilock: no type");
             }
-            body.addrs[N_DIRECT] = 0;
         }
-        body.size = 0;
-        todo!(); // iupdate
+        InodeGuard { inode: self, body }
     }
 }
 
 pub struct InodeBody {
     valid: bool,
-    type_: FileType,
-    major: u16,
-    minor: u16,
-    nlink: u16,
-    size: usize,
-    addrs: [u32; super::N_DIRECT + 1],
+    pub type_: FileType,
+    pub major: u16,
+    pub minor: u16,
+    pub nlink: u16,
+    pub size: usize,
+    addrs: [u32; N_DIRECT + 1],
 }
 impl InodeBody {
     pub const fn zero() -> Self {
@@ -82,43 +85,286 @@ impl InodeBody {
             minor: 0,
             nlink: 0,
             size: 0,
-            addrs: [0; super::N_DIRECT + 1],
+            addrs: [0; N_DIRECT + 1],
+        }
+    }
+}
+
+/// Locked inode
+pub struct InodeGuard<'a> {
+    inode: &'a Inode,
+    body: SleepMutexGuard<'a, InodeBody>,
+}
+impl<'a> core::ops::Deref for InodeGuard<'a> {
+    type Target = InodeBody;
+    fn deref(&self) -> &Self::Target {
+        &*self.body
+    }
+}
+impl<'a> core::ops::DerefMut for InodeGuard<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut *self.body
+    }
+}
+/// Where the address of the bn-th block of an inode is kept
+#[derive(Debug, PartialEq, Eq)]
+enum BlockSlot {
+    /// addrs[i]
+    Direct(usize),
+    /// the i-th entry of the indirect block
+    Indirect(usize),
+}
+
+/// The slot of the bn-th block, or None if bn is beyond the largest file.
+fn block_slot(bn: usize) -> Option<BlockSlot> {
+    if bn < N_DIRECT {
+        Some(BlockSlot::Direct(bn))
+    } else if bn - N_DIRECT < N_INDIRECT {
+        Some(BlockSlot::Indirect(bn - N_DIRECT))
+    } else {
+        None
+    }
+}
+
+/// Split the byte range [off, off + n) at block boundaries into
+/// (block number, offset in the block, length) triples.
+fn block_spans(off: usize, n: usize) -> impl Iterator<Item = (usize, usize, usize)> {
+    let end = off + n;
+    let mut pos = off;
+    core::iter::from_fn(move || {
+        if pos >= end {
+            return None;
+        }
+        let m = usize::min(end - pos, BLK_SIZE - pos % BLK_SIZE);
+        let span = (pos / BLK_SIZE, pos % BLK_SIZE, m);
+        pos += m;
+        Some(span)
+    })
+}
+
+impl<'a> InodeGuard<'a> {
+    /// Copy a modified in-memory inode to disk.
+    /// Must be called after every change to a field
+    /// that lives on disk.
+    pub fn update(&self) {
+        let ip = self.inode;
+        let mut b = bcache::read(ip.dev, super::super_block().inode_block(ip.inum));
+        let dip = {
+            let dinodes = b.data.as_mut_ptr() as *mut OnDiskInode;
+            unsafe { &mut *dinodes.add(ip.inum as usize % INODES_PER_BLOCK) }
+        };
+        dip.type_ = (self.type_ as u16).to_le();
+        dip.major = self.major.to_le();
+        dip.minor = self.minor.to_le();
+        dip.n_link = self.nlink.to_le();
+        dip.size = (self.size as u32).to_le();
+        for (dst, src) in dip.addrs.iter_mut().zip(self.addrs.iter()) {
+            *dst = src.to_le();
+        }
+        log::write(&b);
+    }
+
+    pub fn dev(&self) -> u32 {
+        self.inode.dev
+    }
+    pub fn inum(&self) -> u32 {
+        self.inode.inum
+    }
+
+    /// Copy stat information from inode.
+    pub fn stat(&self) -> Stat {
+        Stat {
+            type_: self.type_,
+            dev: self.inode.dev,
+            ino: self.inode.inum as usize,
+            nlink: self.nlink,
+            size: self.size,
+        }
+    }
+
+    /// Truncate inode (discard contents).
+    /// Called when the inode has no links to it
+    /// (no directory entries referring to it)
+    /// and has no in-memory reference to it
+    /// (is not an open file or current directory).
+    pub fn trunc(&mut self) {
+        let dev = self.inode.dev;
+        for addr in self.addrs[..N_DIRECT].iter_mut() {
+            if *addr != 0 {
+                free_disk_block(dev, *addr);
+                *addr = 0;
+            }
+        }
+        let indirect = self.addrs[N_DIRECT];
+        if indirect != 0 {
+            let b = bcache::read(dev, indirect);
+            let slots = unsafe { &*(b.data.as_ptr() as *const [u32; N_INDIRECT]) };
+            for addr in slots.iter() {
+                if *addr != 0 {
+                    free_disk_block(dev, u32::from_le(*addr));
+                }
+            }
+            drop(b);
+            free_disk_block(dev, indirect);
+            self.addrs[N_DIRECT] = 0;
+        }
+        self.size = 0;
+        self.update();
+    }
+
+    /// The disk block address of the bn-th block in the inode,
+    /// or None if the block has not been allocated.
+    fn block_of(&self, bn: usize) -> Option<u32> {
+        let addr = match block_slot(bn).expect("block_of: out of range") {
+            BlockSlot::Direct(i) => self.addrs[i],
+            BlockSlot::Indirect(i) => {
+                let indirect = self.addrs[N_DIRECT];
+                if indirect == 0 {
+                    return None;
+                }
+                let b = bcache::read(self.inode.dev, indirect);
+                u32::from_le(unsafe { *(b.data.as_ptr() as *const u32).add(i) })
+            }
+        };
+        if addr == 0 {
+            None
+        } else {
+            Some(addr)
+        }
+    }
+
+    /// The disk block address of the bn-th block in the inode.
+    /// If there is no such block, allocate one.
+    fn bmap(&mut self, bn: usize) -> Result<u32> {
+        let dev = self.inode.dev;
+        let bn = match block_slot(bn).expect("bmap: out of range") {
+            BlockSlot::Direct(i) => {
+                if self.addrs[i] == 0 {
+                    self.addrs[i] = alloc_disk_block(dev)?;
+                }
+                return Ok(self.addrs[i]);
+            }
+            BlockSlot::Indirect(i) => i,
+        };
+
+        // Load indirect block, allocating if necessary.
+        if self.addrs[N_DIRECT] == 0 {
+            self.addrs[N_DIRECT] = alloc_disk_block(dev)?;
+        }
+        let mut b = bcache::read(dev, self.addrs[N_DIRECT]);
+        let slot = unsafe { (b.data.as_mut_ptr() as *mut u32).add(bn) };
+        let addr = u32::from_le(unsafe { *slot });
+        if addr != 0 {
+            return Ok(addr);
         }
+        let addr = alloc_disk_block(dev)?;
+        unsafe { *slot = addr.to_le() };
+        log::write(&b);
+        Ok(addr)
     }
 
     /// Read data from inode.
-    fn read(&self, dst: &mut [u8], off: usize) -> Result<usize> {
+    pub fn read(&self, dst: &mut [u8], off: usize) -> Result<usize> {
         if self.type_ == FileType::Device {
-            assert!((self.major as usize) < file::N_DEV);
-            let read = file::dev()[self.major as usize]
-                .read
-                .expect("read function undefined");
-            read(dst)
-        } else {
-            let n = dst.len();
-            if off > self.size || off.wrapping_add(n) < off {
-                return Err(Error::InvalidArg("offset"));
+            let read = file::dev()
+                .get(self.major as usize)
+                .and_then(|d| d.read)
+                .ok_or(Error::InvalidArg("no such device"))?;
+            return read(dst);
+        }
+
+        if off > self.size || off.checked_add(dst.len()).is_none() {
+            return Err(Error::InvalidArg("offset"));
+        }
+        let n = usize::min(dst.len(), self.size - off);
+
+        let mut tot = 0;
+        for (bn, boff, m) in block_spans(off, n) {
+            let dst = &mut dst[tot..tot + m];
+            match self.block_of(bn) {
+                Some(addr) => {
+                    let b = bcache::read(self.inode.dev, addr);
+                    dst.copy_from_slice(&b.data[boff..][..m]);
+                }
+                // A hole reads as zeros.
+                None => dst.iter_mut().for_each(|c| *c = 0),
             }
-            todo!()
+            tot += m;
         }
+        Ok(n)
     }
-    fn write(&self, src: &[u8], off: usize) -> Result<usize> {
-        todo!()
+
+    /// Write data to inode.
+    /// Returns the number of bytes written, which is less than src.len()
+    /// only if the disk got full on the way.
+    pub fn write(&mut self, src: &[u8], off: usize) -> Result<usize> {
+        if self.type_ == FileType::Device {
+            let write = file::dev()
+                .get(self.major as usize)
+                .and_then(|d| d.write)
+                .ok_or(Error::InvalidArg("no such device"))?;
+            return write(src);
+        }
+
+        let n = src.len();
+        match off.checked_add(n) {
+            Some(end) if off <= self.size && end <= MAX_FILE * BLK_SIZE => {}
+            _ => return Err(Error::InvalidArg("offset")),
+        }
+
+        let mut tot = 0;
+        let mut res = Ok(());
+        for (bn, boff, m) in block_spans(off, n) {
+            let addr = match self.bmap(bn) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            };
+            let mut b = bcache::read(self.inode.dev, addr);
+            b.data[boff..][..m].copy_from_slice(&src[tot..tot + m]);
+            log::write(&b);
+            tot += m;
+        }
+
+        if off + tot > self.size {
+            self.size = off + tot;
+        }
+        // write the i-node back to disk even if the size didn't change
+        // because the loop above might have called bmap() and added a new
+        // block to self.addrs.
+        self.update();
+
+        match res {
+            Err(e) if tot == 0 => Err(e),
+            _ => Ok(tot),
+        }
     }
 }
 
 impl Drop for Inode {
+    /// Drop a reference to an in-memory inode.
+    /// If that was the last reference, the inode cache entry can be recycled.
+    /// If that was the last reference and the inode has no links
+    /// to it, free the inode (and its content) on disk.
     fn drop(&mut self) {
-        log!("Inode drop");
-        let mut body = self.body.lock();
-
-        if body.valid && body.nlink == 0 {
+        let mut ip = self.lock_loaded();
+        if ip.valid && ip.nlink == 0 {
             // inode has no links and no other references: truncate and free.
-            // TODO: trunc
-            body.type_ = FileType::Invalid;
-            // TODO: update
-            body.valid = false;
-            todo!();
+            ip.trunc();
+            ip.type_ = FileType::Invalid;
+            ip.update();
+            ip.valid = false;
+        }
+        drop(ip);
+
+        let mut icache = ICACHE.lock();
+        let key = (self.dev, self.inum);
+        if let Some(weak) = icache.cache.get(&key) {
+            if weak.upgrade().is_none() {
+                icache.cache.remove(&key);
+            }
         }
     }
 }
@@ -151,6 +397,29 @@ impl Icache {
     }
 }
 
+/// Allocate an inode on device dev.
+/// Mark it as allocated by giving it type type_.
+/// Returns an unlocked but allocated and referenced inode.
+pub fn ialloc(dev: u32, type_: FileType) -> Result<InodeRef> {
+    let sb = super::super_block();
+    for inum in 1..sb.n_inodes {
+        let mut b = bcache::read(dev, sb.inode_block(inum));
+        let dip = unsafe {
+            let dinodes = b.data.as_mut_ptr() as *mut OnDiskInode;
+            &mut *dinodes.add(inum as usize % INODES_PER_BLOCK)
+        };
+        if dip.type_ == 0 {
+            // a free inode
+            *dip = OnDiskInode::default();
+            dip.type_ = (type_ as u16).to_le();
+            log::write(&b); // mark it allocated on the disk
+            drop(b);
+            return Ok(ICACHE.lock().get(dev, inum));
+        }
+    }
+    Err(Error::NoSpace)
+}
+
 lazy_static! {
     static ref ICACHE: SpinMutex<Icache> = SpinMutex::new("icache", Icache::new());
 }
@@ -159,48 +428,105 @@ pub fn init() {
     lazy_static::initialize(&ICACHE);
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[repr(u16)]
-enum FileType {
+pub enum FileType {
     Invalid = 0,
     Directory = 1,
     File = 2,
     Device = 3,
 }
+impl FileType {
+    fn from_u16(type_: u16) -> Option<Self> {
+        match type_ {
+            0 => Some(FileType::Invalid),
+            1 => Some(FileType::Directory),
+            2 => Some(FileType::File),
+            3 => Some(FileType::Device),
+            _ => None,
+        }
+    }
+}
 
-struct Stat {
-    type_: FileType, // Type of file
-    dev: u32,        // File system's disk device
-    ino: usize,      // Inode number
-    nlink: u16,      // Number of links to file
-    size: usize,     // Size of file in bytes
+/// Fields are ordered so that the struct has no padding,
+/// since it is copied byte for byte to user space.
+#[repr(C)]
+pub struct Stat {
+    pub type_: FileType, // Type of file
+    pub nlink: u16,      // Number of links to file
+    pub dev: u32,        // File system's disk device
+    pub ino: usize,      // Inode number
+    pub size: usize,     // Size of file in bytes
 }
 
-fn dir_lookup(dev: u32, dir: &InodeBody, name: &[u8]) -> Option<(InodeRef, usize)> {
+const DIRENT_SIZE: usize = core::mem::size_of::<DirEnt>();
+
+/// Look for a directory entry in a directory.
+/// If found, return the inode and the byte offset of the entry.
+pub fn dir_lookup(dir: &InodeGuard, name: &[u8]) -> Option<(InodeRef, usize)> {
     if dir.type_ != FileType::Directory {
         panic!("not directory");
     }
-    const SZ: usize = core::mem::size_of::<DirEnt>();
-    let mut de = core::mem::MaybeUninit::zeroed();
+    const SZ: usize = DIRENT_SIZE;
+    let mut de = DirEnt::default();
     let mut off = 0;
     while off < dir.size {
-        {
-            let de_buf = unsafe { core::slice::from_raw_parts_mut(de.as_mut_ptr() as *mut u8, SZ) };
-            if dir.read(de_buf, off).unwrap() != SZ {
-                panic!("dir_loolup: read");
-            }
+        if dir.read(de.as_bytes_mut(), off).unwrap() != SZ {
+            panic!("dir_lookup: read");
         }
-        let de: &DirEnt = unsafe { &*de.as_ptr() };
-        if de.inum != 0 {
-            if name == de.name {
-                return Some((ICACHE.lock().get(dev, de.inum as u32), off));
-            }
+        if de.inum != 0 && de.has_name(name) {
+            let inum = u16::from_le(de.inum) as u32;
+            return Some((ICACHE.lock().get(dir.inode.dev, inum), off));
         }
         off += SZ;
     }
     None
 }
 
+/// Write a new directory entry (name, inum) into the directory dp.
+pub fn dir_link(dp: &mut InodeGuard, name: &[u8], inum: u32) -> Result<()> {
+    // Check that name is not present.
+    if dir_lookup(dp, name).is_some() {
+        return Err(Error::Exists);
+    }
+
+    // Look for an empty dirent.
+    let mut de = DirEnt::default();
+    let mut off = 0;
+    while off < dp.size {
+        if dp.read(de.as_bytes_mut(), off)? != DIRENT_SIZE {
+            panic!("dir_link: read");
+        }
+        if de.inum == 0 {
+            break;
+        }
+        off += DIRENT_SIZE;
+    }
+
+    de.set_name(name);
+    de.inum = (inum as u16).to_le();
+    if dp.write(de.as_bytes(), off)? != DIRENT_SIZE {
+        return Err(Error::NoSpace);
+    }
+    Ok(())
+}
+
+impl<'a> InodeGuard<'a> {
+    /// Is the directory empty except for "." and ".." ?
+    pub fn is_dir_empty(&self) -> bool {
+        let mut de = DirEnt::default();
+        for off in (2 * DIRENT_SIZE..self.size).step_by(DIRENT_SIZE) {
+            if self.read(de.as_bytes_mut(), off).unwrap() != DIRENT_SIZE {
+                panic!("is_dir_empty: read");
+            }
+            if de.inum != 0 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
 // Split the path at the end of the first path element.
 // Return a pair of slices.
 // One is a first path element and the other is the remainder.
@@ -236,42 +562,53 @@ fn split_first(path: &[u8]) -> Option<(&[u8], &[u8])> {
     Some((first_elem, skip_leading_slash(path)))
 }
 
-fn name_x(path: &str, name_iparent: bool) -> Option<InodeRef> {
-    let mut ip = match path {
-        "/" => ICACHE.lock().get(ROOT_DEV, ROOT_INO),
-        _ => {
+/// Look up and return the inode for a path name.
+/// If parent is true, return the inode for the parent and the final
+/// path element instead.
+fn name_x(path: &str, parent: bool) -> Option<(InodeRef, &[u8])> {
+    let mut ip = match path.starts_with('/') {
+        true => ICACHE.lock().get(ROOT_DEV, ROOT_INO),
+        false => {
             // start traverse from the current working directory
             my_proc().lock().cwd.as_ref().unwrap().clone()
         }
     };
 
+    let mut last: &[u8] = &[];
     let mut path = path.as_bytes();
     while let Some((name, path_)) = split_first(path) {
         path = path_;
+        last = name;
 
-        let body = ip.body.lock();
-        if body.type_ != FileType::Directory {
+        let guard = ip.lock();
+        if guard.type_ != FileType::Directory {
             return None;
         }
 
-        if name_iparent && path.is_empty() {
-            drop(body);
-            return Some(ip);
+        if parent && path.is_empty() {
+            // Stop one level early.
+            drop(guard);
+            return Some((ip, name));
         }
 
-        let (next, _) = dir_lookup(ip.dev, &body, name)?;
-        drop(body);
+        let (next, _) = dir_lookup(&guard, name)?;
+        drop(guard);
         ip = next;
     }
 
-    if name_iparent {
+    if parent {
         None
     } else {
-        Some(ip)
+        Some((ip, last))
     }
 }
 pub fn from_name(path: &str) -> Option<InodeRef> {
-    name_x(path, false)
+    name_x(path, false).map(|(ip, _)| ip)
+}
+/// Return the inode of the parent directory and the final path element.
+/// Fails for paths without any element such as "/".
+pub fn from_name_parent(path: &str) -> Option<(InodeRef, &[u8])> {
+    name_x(path, true)
 }
 
 #[cfg(test)]
@@ -300,4 +637,55 @@ mod tests {
         let path = b"////";
         assert!(split_first(path).is_none());
     }
+
+    #[test_case]
+    fn test_block_slot() {
+        assert_eq!(block_slot(0), Some(BlockSlot::Direct(0)));
+        assert_eq!(
+            block_slot(N_DIRECT - 1),
+            Some(BlockSlot::Direct(N_DIRECT - 1))
+        );
+        assert_eq!(block_slot(N_DIRECT), Some(BlockSlot::Indirect(0)));
+        assert_eq!(
+            block_slot(MAX_FILE - 1),
+            Some(BlockSlot::Indirect(N_INDIRECT - 1))
+        );
+        assert_eq!(block_slot(MAX_FILE), None);
+    }
+
+    #[test_case]
+    fn test_block_spans() {
+        let spans = |off, n| block_spans(off, n).collect::<alloc::vec::Vec<_>>();
+        assert!(spans(0, 0).is_empty());
+        assert_eq!(spans(0, BLK_SIZE), [(0, 0, BLK_SIZE)]);
+        assert_eq!(spans(10, 20), [(0, 10, 20)]);
+        // Unaligned start and end spanning three blocks
+        assert_eq!(
+            spans(BLK_SIZE - 1, BLK_SIZE + 2),
+            [(0, BLK_SIZE - 1, 1), (1, 0, BLK_SIZE), (2, 0, 1)]
+        );
+        // Ending exactly at a block boundary
+        assert_eq!(
+            spans(2 * BLK_SIZE + 5, BLK_SIZE - 5),
+            [(2, 5, BLK_SIZE - 5)]
+        );
+    }
+
+    #[test_case]
+    fn test_dir_ent_name() {
+        let mut de = DirEnt::default();
+        de.set_name(b"abc");
+        assert_eq!(de.name(), b"abc");
+        assert!(de.has_name(b"abc"));
+        assert!(!de.has_name(b"ab"));
+        assert!(!de.has_name(b"abcd"));
+
+        // Names are truncated to 14 bytes and must still be found.
+        de.set_name(b"0123456789abcdef");
+        assert_eq!(de.name(), b"0123456789abcd");
+        assert!(de.has_name(b"0123456789abcdef"));
+        assert!(de.has_name(b"0123456789abcd"));
+        assert!(de.has_name(b"0123456789abcdXY"));
+        assert!(!de.has_name(b"0123456789abc"));
+    }
 }
@@ -0,0 +1,246 @@
+//! Simple logging that allows concurrent FS system calls.
+//!
+//! A log transaction contains the updates of multiple FS system
+//! calls. The logging system only commits when there are
+//! no FS system calls active. Thus there is never
+//! any reasoning required about whether a commit might
+//! write an uncommitted system call's updates to disk.
+//!
+//! A system call should call begin_op() to mark its start and keep
+//! the returned Transaction until its end. Usually begin_op() just
+//! increments the count of in-progress FS system calls and returns.
+//! But if it thinks the log is close to running out, it sleeps
+//! until the last outstanding Transaction is dropped.
+//!
+//! The log is a physical re-do log containing disk blocks.
+//! The on-disk log format:
+//!   header block, containing block #s for block A, B, C, ...
+//!   block A
+//!   block B
+//!   block C
+//!   ...
+//! Log appends are synchronous.
+
+use super::bcache::{self, Buf};
+use crate::lock::spin::SpinMutex;
+use crate::proc;
+use utils::fs::{SuperBlock, LOG_SIZE, MAX_OP_BLOCKS};
+
+/// Contents of the header block, used for both the on-disk header block
+/// and to keep track in memory of logged block# before commit.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct LogHeader {
+    n: u32,
+    block: [u32; LOG_SIZE],
+}
+
+struct Log {
+    start: u32,
+    size: u32,
+    /// how many FS sys calls are executing.
+    outstanding: usize,
+    /// in commit(), please wait.
+    committing: bool,
+    dev: u32,
+    header: LogHeader,
+}
+
+static LOG: SpinMutex<Log> = SpinMutex::new(
+    "log",
+    Log {
+        start: 0,
+        size: 0,
+        outstanding: 0,
+        committing: false,
+        dev: 0,
+        header: LogHeader {
+            n: 0,
+            block: [0; LOG_SIZE],
+        },
+    },
+);
+
+fn log_chan() -> usize {
+    &LOG as *const _ as usize
+}
+
+/// Set up the log of dev and recover from it if there is a committed transaction.
+pub fn init(dev: u32, sb: &SuperBlock) {
+    assert!(
+        core::mem::size_of::<LogHeader>() < utils::fs::BLK_SIZE,
+        "log::init: too big LogHeader"
+    );
+    {
+        let mut log = LOG.lock();
+        log.start = sb.log_start;
+        log.size = sb.n_log;
+        log.dev = dev;
+    }
+    recover();
+}
+
+/// Copy committed blocks from log to their home location
+fn install_trans(header: &LogHeader, start: u32, dev: u32, recovering: bool) {
+    for (tail, &block_no) in header.block[..header.n as usize].iter().enumerate() {
+        let lbuf = bcache::read(dev, start + tail as u32 + 1); // read log block
+        let mut dbuf = bcache::read(dev, block_no); // read dst
+        dbuf.data = lbuf.data; // copy block to dst
+        dbuf.write(); // write dst to disk
+        if !recovering {
+            bcache::unpin(&dbuf);
+        }
+    }
+}
+
+/// Read the log header from disk into the in-memory log header
+fn read_head(start: u32, dev: u32) -> LogHeader {
+    let buf = bcache::read(dev, start);
+    let mut header = unsafe { core::ptr::read_unaligned(buf.data.as_ptr() as *const LogHeader) };
+    header.n = u32::from_le(header.n);
+    for b in header.block.iter_mut() {
+        *b = u32::from_le(*b);
+    }
+    header
+}
+
+/// Write in-memory log header to disk.
+/// This is the true point at which the
+/// current transaction commits.
+fn write_head(header: &LogHeader, start: u32, dev: u32) {
+    let mut buf = bcache::read(dev, start);
+    let mut disk = *header;
+    disk.n = disk.n.to_le();
+    for b in disk.block.iter_mut() {
+        *b = b.to_le();
+    }
+    unsafe { core::ptr::write_unaligned(buf.data.as_mut_ptr() as *mut LogHeader, disk) };
+    buf.write();
+}
+
+fn recover() {
+    let (start, dev) = {
+        let log = LOG.lock();
+        (log.start, log.dev)
+    };
+    let mut header = read_head(start, dev);
+    if header.n > 0 {
+        log!("log: recovering {} blocks", header.n);
+    }
+    install_trans(&header, start, dev, true); // if committed, copy from log to disk
+    header.n = 0;
+    write_head(&header, start, dev); // clear the log
+    LOG.lock().header = header;
+}
+
+/// An FS operation in progress. Dropping it ends the operation
+/// and commits the log if this was the last outstanding one.
+pub struct Transaction {
+    _private: (),
+}
+
+/// Called at the start of each FS system call.
+pub fn begin_op() -> Transaction {
+    let mut log = LOG.lock();
+    loop {
+        if log.committing
+            || log.header.n as usize + (log.outstanding + 1) * MAX_OP_BLOCKS > LOG_SIZE
+        {
+            // wait for the log to be committed,
+            // or this op might exhaust log space.
+            proc::sleep(log_chan(), &log);
+        } else {
+            log.outstanding += 1;
+            break;
+        }
+    }
+    Transaction { _private: () }
+}
+
+impl Drop for Transaction {
+    /// Called at the end of each FS system call.
+    /// Commits if this was the last outstanding operation.
+    fn drop(&mut self) {
+        let do_commit = {
+            let mut log = LOG.lock();
+            log.outstanding -= 1;
+            assert!(!log.committing, "log.committing");
+            if log.outstanding == 0 {
+                log.committing = true;
+                true
+            } else {
+                // begin_op() may be waiting for log space,
+                // and decrementing log.outstanding has decreased
+                // the amount of reserved space.
+                proc::wakeup(log_chan());
+                false
+            }
+        };
+
+        if do_commit {
+            // call commit w/o holding locks, since not allowed
+            // to sleep with locks.
+            commit();
+            let mut log = LOG.lock();
+            log.committing = false;
+            proc::wakeup(log_chan());
+        }
+    }
+}
+
+/// Copy modified blocks from cache to log.
+fn write_log(header: &LogHeader, start: u32, dev: u32) {
+    for (tail, &block_no) in header.block[..header.n as usize].iter().enumerate() {
+        let mut to = bcache::read(dev, start + tail as u32 + 1); // log block
+        let from = bcache::read(dev, block_no); // cache block
+        to.data = from.data;
+        to.write(); // write the log
+    }
+}
+
+fn commit() {
+    // No other process touches the header while committing is set.
+    let (mut header, start, dev) = {
+        let log = LOG.lock();
+        (log.header, log.start, log.dev)
+    };
+    if header.n > 0 {
+        write_log(&header, start, dev); // Write modified blocks from cache to log
+        write_head(&header, start, dev); // Write header to disk -- the real commit
+        install_trans(&header, start, dev, false); // Now install writes to home locations
+        header.n = 0;
+        write_head(&header, start, dev); // Erase the transaction from the log
+        LOG.lock().header = header;
+    }
+}
+
+/// Caller has modified b.data and is done with the buffer.
+/// Record the block number and pin in the cache by increasing the refcount.
+/// commit()/write_log() will do the disk write.
+///
+/// write() replaces Buf::write(); a typical use is:
+///   let mut b = bcache::read(...);
+///   modify b.data
+///   log::write(&b);
+pub fn write(b: &Buf) {
+    let mut log = LOG.lock();
+    let n = log.header.n as usize;
+    if n >= LOG_SIZE || n as u32 + 1 >= log.size {
+        panic!("too big a transaction");
+    }
+    if log.outstanding < 1 {
+        panic!("log::write outside of trans");
+    }
+
+    // log absorption
+    let i = log.header.block[..n]
+        .iter()
+        .position(|&block_no| block_no == b.block_no);
+    if i.is_none() {
+        // Add new block to log
+        log.header.block[n] = b.block_no;
+        log.header.n += 1;
+        bcache::pin(b);
+    }
+    b.flags.set_dirty(true);
+}
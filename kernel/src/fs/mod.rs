@@ -1,89 +1,164 @@
 pub mod bcache;
+pub mod file;
 pub mod ide;
 pub mod inode;
+pub mod log;
+pub mod pipe;
 
-const BLK_SIZE: usize = 512;
-const N_DIRECT: usize = 12;
-const N_INDIRECT: usize = BLK_SIZE / core::mem::size_of::<u32>();
+use utils::fs::{SuperBlock, BITS_PER_BLOCK, BLK_SIZE};
 
-// Directory is a file containing a sequence of dirent structures.
-const DIR_SIZE: usize = 14;
+/// Device number of file system root disk
+pub const ROOT_DEV: u32 = 1;
 
 type BlockNum = u32;
 
-struct SuperBlock {
-    /// Size of file system image (blocks)
-    size: usize,
-    /// Number of data blocks
-    nblocks: usize,
-    /// Number of inodes
-    ninodes: usize,
-    /// Number of log blocks
-    nlog: usize,
-    /// Block number of first log block
-    log_start: BlockNum,
-    /// Block number of first inode block
-    inode_start: BlockNum,
-    /// Block number of first free map block
-    bmap_start: BlockNum,
+/// There should be one superblock per disk device, but we run with only one device.
+static mut SUPER_BLOCK: Option<SuperBlock> = None;
+fn super_block() -> &'static SuperBlock {
+    unsafe { SUPER_BLOCK.as_ref().expect("file system not mounted") }
 }
-impl SuperBlock {
-    pub fn read(&mut self, dev: usize) {
-        todo!()
+
+/// Read the super block of dev.
+fn read_super_block(dev: u32) -> SuperBlock {
+    let b = bcache::read(dev, 1);
+    let sb = unsafe { core::ptr::read_unaligned(b.data.as_ptr() as *const SuperBlock) };
+    SuperBlock {
+        magic: u32::from_le(sb.magic),
+        size: u32::from_le(sb.size),
+        n_blocks: u32::from_le(sb.n_blocks),
+        n_inodes: u32::from_le(sb.n_inodes),
+        n_log: u32::from_le(sb.n_log),
+        log_start: u32::from_le(sb.log_start),
+        inode_start: u32::from_le(sb.inode_start),
+        bmap_start: u32::from_le(sb.bmap_start),
     }
 }
 
-#[repr(C)]
-struct DirEnt {
-    inum: u16,
-    name: [u8; DIR_SIZE],
-}
-impl DirEnt {
-    pub const fn zero() -> Self {
-        Self {
-            inum: 0,
-            name: [0; DIR_SIZE],
-        }
+/// Read and validate the super block of the root device,
+/// then recover the file system from the log.
+/// Must be called in the context of a regular process since it reads the disk.
+pub fn mount(dev: u32) {
+    let sb = read_super_block(dev);
+    if !sb.is_consistent() {
+        panic!("mount: invalid super block {:?}", sb);
+    }
+    if sb.size > ide::capacity(dev) {
+        panic!(
+            "mount: file system size {} exceeds disk size {}",
+            sb.size,
+            ide::capacity(dev)
+        );
     }
+    log!(
+        "sb: size {} nblocks {} ninodes {} nlog {} logstart {} inodestart {} bmap start {}",
+        sb.size,
+        sb.n_blocks,
+        sb.n_inodes,
+        sb.n_log,
+        sb.log_start,
+        sb.inode_start,
+        sb.bmap_start
+    );
+    log::init(dev, &sb);
+    unsafe { SUPER_BLOCK = Some(sb) };
 }
 
-pub fn free_disk_block(dev: u32, block_no: u32) {
-    todo!()
+const BPB: u32 = BITS_PER_BLOCK as u32;
+
+/// Zero a block.
+fn zero_disk_block(dev: u32, block_no: BlockNum) {
+    let mut b = bcache::read(dev, block_no);
+    b.data = [0; BLK_SIZE];
+    log::write(&b);
 }
 
-#[derive(Debug)]
-pub enum Error {
-    InvalidArg(&'static str),
+/// Find a clear bit among the first n bits of bitmap, set it and return its index.
+fn set_free_bit(bitmap: &mut [u8], n: u32) -> Option<u32> {
+    for bi in 0..n {
+        let m = 1 << (bi % 8);
+        let byte = &mut bitmap[(bi / 8) as usize];
+        if *byte & m == 0 {
+            *byte |= m;
+            return Some(bi);
+        }
+    }
+    None
 }
-pub type Result<T> = core::result::Result<T, Error>;
 
-pub mod file {
-    pub const N_DEV: usize = 10;
+/// Clear bit bi of bitmap, which must be set.
+fn clear_bit(bitmap: &mut [u8], bi: u32) {
+    let m = 1 << (bi % 8);
+    let byte = &mut bitmap[(bi / 8) as usize];
+    assert!(*byte & m != 0, "freeing free block");
+    *byte &= !m;
+}
 
-    /// table mapping major device number to device functions
-    pub struct Dev {
-        pub read: Option<fn(&mut [u8]) -> super::Result<usize>>,
-        pub write: Option<fn(&[u8]) -> super::Result<usize>>,
-    }
-    static mut DEV: [Dev; N_DEV] = [Dev {
-        read: None,
-        write: None,
-    }; N_DEV];
-
-    /// Safe wrapper for DEV
-    pub fn dev() -> &'static [Dev] {
-        unsafe { &DEV[..] }
+/// Allocate a zeroed disk block.
+pub fn alloc_disk_block(dev: u32) -> Result<BlockNum> {
+    let size = super_block().size as BlockNum;
+    for base in (0..size).step_by(BPB as usize) {
+        let mut b = bcache::read(dev, super_block().bitmap_block(base));
+        // Mark a free block in use.
+        if let Some(bi) = set_free_bit(&mut b.data, u32::min(BPB, size - base)) {
+            log::write(&b);
+            drop(b);
+            zero_disk_block(dev, base + bi);
+            return Ok(base + bi);
+        }
     }
+    Err(Error::NoSpace)
+}
 
-    pub unsafe fn init_dev(dev_num: u32, dev: Dev) {
-        DEV[dev_num as usize] = dev;
-    }
+/// Free a disk block.
+pub fn free_disk_block(dev: u32, block_no: BlockNum) {
+    let mut b = bcache::read(dev, super_block().bitmap_block(block_no));
+    clear_bit(&mut b.data, block_no % BPB);
+    log::write(&b);
+}
 
-    pub const CONSOLE: u32 = 1;
+#[derive(Debug)]
+pub enum Error {
+    InvalidArg(&'static str),
+    /// No free blocks or inodes left on the device
+    NoSpace,
+    /// Directory entry already exists
+    Exists,
+    /// Write to a pipe with no reader
+    BrokenPipe,
+    /// The process has been killed while waiting
+    Interrupted,
 }
+pub type Result<T> = core::result::Result<T, Error>;
 
 pub fn init() {
     ide::init();
     bcache::init();
     inode::init();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_bitmap() {
+        let mut bitmap = [0u8; 2];
+        assert_eq!(set_free_bit(&mut bitmap, 16), Some(0));
+        assert_eq!(set_free_bit(&mut bitmap, 16), Some(1));
+        assert_eq!(bitmap, [0b11, 0]);
+
+        // Crossing a byte boundary
+        bitmap = [0xFF, 0];
+        assert_eq!(set_free_bit(&mut bitmap, 16), Some(8));
+        assert_eq!(bitmap, [0xFF, 0b1]);
+
+        // A freed bit is reused first.
+        clear_bit(&mut bitmap, 3);
+        assert_eq!(bitmap, [0xF7, 0b1]);
+        assert_eq!(set_free_bit(&mut bitmap, 16), Some(3));
+
+        // Bits beyond n are never handed out.
+        bitmap = [0xFF, 0];
+        assert_eq!(set_free_bit(&mut bitmap, 8), None);
+    }
+}
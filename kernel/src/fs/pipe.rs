@@ -0,0 +1,129 @@
+//! Pipes
+
+use super::file::{File, FileKind, FileRef};
+use super::{Error, Result};
+use crate::lock::spin::SpinMutex;
+use crate::proc;
+use alloc::sync::Arc;
+
+const PIPE_SIZE: usize = 512;
+
+struct PipeInner {
+    data: [u8; PIPE_SIZE],
+    /// number of bytes read
+    nread: usize,
+    /// number of bytes written
+    nwrite: usize,
+    /// read fd is still open
+    read_open: bool,
+    /// write fd is still open
+    write_open: bool,
+}
+impl PipeInner {
+    fn is_empty(&self) -> bool {
+        self.nread == self.nwrite
+    }
+}
+
+pub struct Pipe {
+    inner: SpinMutex<PipeInner>,
+}
+pub type PipeRef = Arc<Pipe>;
+
+/// Create a pipe and return the file for its read end and write end.
+pub fn alloc() -> (FileRef, FileRef) {
+    let pipe = Arc::new(Pipe {
+        inner: SpinMutex::new(
+            "pipe",
+            PipeInner {
+                data: [0; PIPE_SIZE],
+                nread: 0,
+                nwrite: 0,
+                read_open: true,
+                write_open: true,
+            },
+        ),
+    });
+    let rf = File::new(FileKind::Pipe(pipe.clone()), true, false);
+    let wf = File::new(FileKind::Pipe(pipe), false, true);
+    (rf, wf)
+}
+
+impl Pipe {
+    /// Channel on which readers wait for data
+    fn read_chan(&self) -> usize {
+        self as *const _ as usize
+    }
+    /// Channel on which writers wait for space
+    fn write_chan(&self) -> usize {
+        self as *const _ as usize + 1
+    }
+
+    /// Close one end of the pipe.
+    pub fn close(&self, writable: bool) {
+        let mut p = self.inner.lock();
+        if writable {
+            p.write_open = false;
+            proc::wakeup(self.read_chan());
+        } else {
+            p.read_open = false;
+            proc::wakeup(self.write_chan());
+        }
+    }
+
+    pub fn write(&self, src: &[u8]) -> Result<usize> {
+        let mut p = self.inner.lock();
+        for (i, c) in src.iter().enumerate() {
+            loop {
+                if !p.read_open {
+                    // Nobody will ever read the rest.
+                    proc::wakeup(self.read_chan());
+                    return if i == 0 {
+                        Err(Error::BrokenPipe)
+                    } else {
+                        Ok(i)
+                    };
+                }
+                if p.nwrite < p.nread + PIPE_SIZE {
+                    break;
+                }
+                // pipe is full
+                if proc::killed() {
+                    // Report what has already been written, if anything.
+                    proc::wakeup(self.read_chan());
+                    return if i == 0 {
+                        Err(Error::Interrupted)
+                    } else {
+                        Ok(i)
+                    };
+                }
+                proc::wakeup(self.read_chan());
+                proc::sleep(self.write_chan(), &p);
+            }
+            let idx = p.nwrite % PIPE_SIZE;
+            p.data[idx] = *c;
+            p.nwrite += 1;
+        }
+        proc::wakeup(self.read_chan());
+        Ok(src.len())
+    }
+
+    pub fn read(&self, dst: &mut [u8]) -> Result<usize> {
+        let mut p = self.inner.lock();
+        while p.is_empty() && p.write_open {
+            // pipe is empty
+            if proc::killed() {
+                return Err(Error::Interrupted);
+            }
+            proc::sleep(self.read_chan(), &p);
+        }
+        let mut n = 0;
+        while n < dst.len() && p.nread != p.nwrite {
+            dst[n] = p.data[p.nread % PIPE_SIZE];
+            p.nread += 1;
+            n += 1;
+        }
+        proc::wakeup(self.write_chan());
+        Ok(n)
+    }
+}
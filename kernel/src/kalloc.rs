@@ -1,7 +1,8 @@
 use super::lock::spin::SpinMutex;
-use super::memory::{Page, PAGE_SIZE};
+use super::memory::{v2p, Page, PAGE_SIZE, PHYSTOP};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU16, Ordering};
 use utils::prelude::*;
 
 use linked_list_allocator::Heap;
@@ -56,9 +57,27 @@ pub fn init2(start: VAddr<u8>, end: VAddr<u8>) {
     unsafe { HEAP.extend(size) };
 }
 
-/// Free the page of physical memory pointed at by page,
+const N_FRAMES: usize = PHYSTOP.raw() / PAGE_SIZE;
+
+/// Number of references to each physical page returned by kalloc(),
+/// indexed by physical page number.
+/// Pages shared copy-on-write by several processes have more than one.
+static REF_COUNTS: [AtomicU16; N_FRAMES] = [AtomicU16::new(0); N_FRAMES];
+
+fn ref_count_of(page: NonNull<Page>) -> &'static AtomicU16 {
+    let pa = v2p(VAddr::from(page.as_ptr() as *const Page));
+    &REF_COUNTS[pa.raw() / PAGE_SIZE]
+}
+
+/// Drop a reference to the page of physical memory pointed at by page,
 /// which normally should have been returned by a call to kalloc().
+/// The page is freed when the last reference is dropped.
 pub fn kfree(page: NonNull<Page>) {
+    let prev = ref_count_of(page).fetch_sub(1, Ordering::SeqCst);
+    assert!(prev > 0, "kfree: page not allocated");
+    if prev > 1 {
+        return;
+    }
     let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
     unsafe { HEAP.dealloc(page.as_ptr() as *mut u8, layout) };
 }
@@ -69,5 +88,18 @@ pub fn kfree(page: NonNull<Page>) {
 pub fn kalloc() -> Option<NonNull<Page>> {
     let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
     let page = unsafe { HEAP.alloc(layout) };
-    NonNull::new(page as *mut Page)
+    let page = NonNull::new(page as *mut Page)?;
+    ref_count_of(page).store(1, Ordering::SeqCst);
+    Some(page)
+}
+
+/// Add a reference to a page returned by kalloc().
+pub fn incref(page: NonNull<Page>) {
+    let prev = ref_count_of(page).fetch_add(1, Ordering::SeqCst);
+    assert!(prev > 0, "incref: page not allocated");
+}
+
+/// Number of references to a page returned by kalloc().
+pub fn ref_count(page: NonNull<Page>) -> usize {
+    ref_count_of(page).load(Ordering::SeqCst) as usize
 }
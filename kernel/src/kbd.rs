@@ -0,0 +1,296 @@
+//! PS/2 keyboard driver (scan code set 1)
+
+use super::lock::spin::SpinMutex;
+use super::trap::{self, TrapFrame};
+use super::{console, ioapic};
+use utils::assigned_array;
+use utils::x86;
+
+/// kbd controller status port(I)
+const KBS_STATP: u16 = 0x64;
+/// kbd data in buffer
+const KBS_DIB: u8 = 0x01;
+/// kbd data port(I)
+const KBDATAP: u16 = 0x60;
+
+const NO: u8 = 0;
+
+// Modifier state
+const SHIFT: u8 = 1 << 0;
+const CTL: u8 = 1 << 1;
+
+const CAPSLOCK: u8 = 1 << 3;
+const NUMLOCK: u8 = 1 << 4;
+const SCROLLLOCK: u8 = 1 << 5;
+
+/// The next scan code belongs to an extended (0xE0-prefixed) key.
+const E0ESC: u8 = 1 << 6;
+
+// Special keycodes
+pub const KEY_HOME: u8 = 0xE0;
+pub const KEY_END: u8 = 0xE1;
+pub const KEY_UP: u8 = 0xE2;
+pub const KEY_DN: u8 = 0xE3;
+pub const KEY_LF: u8 = 0xE4;
+pub const KEY_RT: u8 = 0xE5;
+pub const KEY_PGUP: u8 = 0xE6;
+pub const KEY_PGDN: u8 = 0xE7;
+pub const KEY_INS: u8 = 0xE8;
+pub const KEY_DEL: u8 = 0xE9;
+
+/// Control-x
+const fn ctrl(c: u8) -> u8 {
+    c.wrapping_sub(b'@')
+}
+
+/// Keys which are held down, indexed by scan code.
+/// Extended keys are stored at `code | 0x80`.
+static SHIFT_CODE: [u8; 256] = assigned_array![
+    NO; 256;
+    [0x1D] = CTL,
+    [0x2A] = SHIFT,
+    [0x36] = SHIFT,
+    [0x9D] = CTL
+];
+
+/// Keys which toggle a lock state on each press.
+static TOGGLE_CODE: [u8; 256] = assigned_array![
+    NO; 256;
+    [0x3A] = CAPSLOCK,
+    [0x45] = NUMLOCK,
+    [0x46] = SCROLLLOCK
+];
+
+/// Characters of the keys shared by all maps
+macro_rules! key_map {
+    ($([$idx:expr] = $val:expr),*) => {
+        assigned_array![
+            NO; 256;
+            // keypad
+            [0x47] = b'7', [0x48] = b'8', [0x49] = b'9', [0x4A] = b'-',
+            [0x4B] = b'4', [0x4C] = b'5', [0x4D] = b'6', [0x4E] = b'+',
+            [0x4F] = b'1', [0x50] = b'2', [0x51] = b'3', [0x52] = b'0',
+            [0x53] = b'.',
+            // extended keys
+            [0x9C] = b'\n', // KP_Enter
+            [0xB5] = b'/', // KP_Div
+            [0xC7] = KEY_HOME, [0xC8] = KEY_UP, [0xC9] = KEY_PGUP,
+            [0xCB] = KEY_LF, [0xCD] = KEY_RT,
+            [0xCF] = KEY_END, [0xD0] = KEY_DN, [0xD1] = KEY_PGDN,
+            [0xD2] = KEY_INS, [0xD3] = KEY_DEL,
+            $([$idx] = $val),*
+        ]
+    };
+}
+
+static NORMAL_MAP: [u8; 256] = key_map![
+    [0x01] = 0x1B, // Esc
+    [0x02] = b'1',
+    [0x03] = b'2',
+    [0x04] = b'3',
+    [0x05] = b'4',
+    [0x06] = b'5',
+    [0x07] = b'6',
+    [0x08] = b'7',
+    [0x09] = b'8',
+    [0x0A] = b'9',
+    [0x0B] = b'0',
+    [0x0C] = b'-',
+    [0x0D] = b'=',
+    [0x0E] = b'\x08',
+    [0x0F] = b'\t',
+    [0x10] = b'q',
+    [0x11] = b'w',
+    [0x12] = b'e',
+    [0x13] = b'r',
+    [0x14] = b't',
+    [0x15] = b'y',
+    [0x16] = b'u',
+    [0x17] = b'i',
+    [0x18] = b'o',
+    [0x19] = b'p',
+    [0x1A] = b'[',
+    [0x1B] = b']',
+    [0x1C] = b'\n',
+    [0x1E] = b'a',
+    [0x1F] = b's',
+    [0x20] = b'd',
+    [0x21] = b'f',
+    [0x22] = b'g',
+    [0x23] = b'h',
+    [0x24] = b'j',
+    [0x25] = b'k',
+    [0x26] = b'l',
+    [0x27] = b';',
+    [0x28] = b'\'',
+    [0x29] = b'`',
+    [0x2B] = b'\\',
+    [0x2C] = b'z',
+    [0x2D] = b'x',
+    [0x2E] = b'c',
+    [0x2F] = b'v',
+    [0x30] = b'b',
+    [0x31] = b'n',
+    [0x32] = b'm',
+    [0x33] = b',',
+    [0x34] = b'.',
+    [0x35] = b'/',
+    [0x37] = b'*',
+    [0x39] = b' '
+];
+
+static SHIFT_MAP: [u8; 256] = key_map![
+    [0x01] = 0x1B, // Esc
+    [0x02] = b'!',
+    [0x03] = b'@',
+    [0x04] = b'#',
+    [0x05] = b'$',
+    [0x06] = b'%',
+    [0x07] = b'^',
+    [0x08] = b'&',
+    [0x09] = b'*',
+    [0x0A] = b'(',
+    [0x0B] = b')',
+    [0x0C] = b'_',
+    [0x0D] = b'+',
+    [0x0E] = b'\x08',
+    [0x0F] = b'\t',
+    [0x10] = b'Q',
+    [0x11] = b'W',
+    [0x12] = b'E',
+    [0x13] = b'R',
+    [0x14] = b'T',
+    [0x15] = b'Y',
+    [0x16] = b'U',
+    [0x17] = b'I',
+    [0x18] = b'O',
+    [0x19] = b'P',
+    [0x1A] = b'{',
+    [0x1B] = b'}',
+    [0x1C] = b'\n',
+    [0x1E] = b'A',
+    [0x1F] = b'S',
+    [0x20] = b'D',
+    [0x21] = b'F',
+    [0x22] = b'G',
+    [0x23] = b'H',
+    [0x24] = b'J',
+    [0x25] = b'K',
+    [0x26] = b'L',
+    [0x27] = b':',
+    [0x28] = b'"',
+    [0x29] = b'~',
+    [0x2B] = b'|',
+    [0x2C] = b'Z',
+    [0x2D] = b'X',
+    [0x2E] = b'C',
+    [0x2F] = b'V',
+    [0x30] = b'B',
+    [0x31] = b'N',
+    [0x32] = b'M',
+    [0x33] = b'<',
+    [0x34] = b'>',
+    [0x35] = b'?',
+    [0x37] = b'*',
+    [0x39] = b' '
+];
+
+static CTL_MAP: [u8; 256] = key_map![
+    [0x10] = ctrl(b'Q'),
+    [0x11] = ctrl(b'W'),
+    [0x12] = ctrl(b'E'),
+    [0x13] = ctrl(b'R'),
+    [0x14] = ctrl(b'T'),
+    [0x15] = ctrl(b'Y'),
+    [0x16] = ctrl(b'U'),
+    [0x17] = ctrl(b'I'),
+    [0x18] = ctrl(b'O'),
+    [0x19] = ctrl(b'P'),
+    [0x1C] = b'\r',
+    [0x1E] = ctrl(b'A'),
+    [0x1F] = ctrl(b'S'),
+    [0x20] = ctrl(b'D'),
+    [0x21] = ctrl(b'F'),
+    [0x22] = ctrl(b'G'),
+    [0x23] = ctrl(b'H'),
+    [0x24] = ctrl(b'J'),
+    [0x25] = ctrl(b'K'),
+    [0x26] = ctrl(b'L'),
+    [0x2B] = ctrl(b'\\'),
+    [0x2C] = ctrl(b'Z'),
+    [0x2D] = ctrl(b'X'),
+    [0x2E] = ctrl(b'C'),
+    [0x2F] = ctrl(b'V'),
+    [0x30] = ctrl(b'B'),
+    [0x31] = ctrl(b'N'),
+    [0x32] = ctrl(b'M'),
+    [0xB5] = ctrl(b'/')
+];
+
+/// Current modifier and lock state
+static SHIFT_STATE: SpinMutex<u8> = SpinMutex::new("kbd", 0);
+
+/// Read a scan code from the controller and translate it.
+/// Returns None if there was no data or the code does not produce a character.
+fn getc() -> Option<u8> {
+    if x86::inb(KBS_STATP) & KBS_DIB == 0 {
+        return None;
+    }
+    let mut data = x86::inb(KBDATAP);
+
+    let mut shift = SHIFT_STATE.lock();
+    if data == 0xE0 {
+        *shift |= E0ESC;
+        return None;
+    } else if data & 0x80 != 0 {
+        // Key released
+        let code = if *shift & E0ESC != 0 {
+            data
+        } else {
+            data & 0x7F
+        };
+        *shift &= !(SHIFT_CODE[code as usize] | E0ESC);
+        return None;
+    } else if *shift & E0ESC != 0 {
+        // Last character was an E0 escape; or with 0x80
+        data |= 0x80;
+        *shift &= !E0ESC;
+    }
+
+    *shift |= SHIFT_CODE[data as usize];
+    *shift ^= TOGGLE_CODE[data as usize];
+    let map = match *shift & (CTL | SHIFT) {
+        0 => &NORMAL_MAP,
+        SHIFT => &SHIFT_MAP,
+        _ => &CTL_MAP,
+    };
+    let mut c = map[data as usize];
+    if *shift & CAPSLOCK != 0 {
+        if c.is_ascii_lowercase() {
+            c = c.to_ascii_uppercase();
+        } else if c.is_ascii_uppercase() {
+            c = c.to_ascii_lowercase();
+        }
+    }
+    if c == NO {
+        None
+    } else {
+        Some(c)
+    }
+}
+
+fn kbd_intr(_: &mut TrapFrame) {
+    while x86::inb(KBS_STATP) & KBS_DIB != 0 {
+        match getc() {
+            // The console has no line editing with cursor keys.
+            Some(c) if c >= KEY_HOME => {}
+            Some(c) => console::intr(c),
+            None => {}
+        }
+    }
+}
+
+pub fn init() {
+    unsafe { trap::register_handler(trap::T_IRQ0 + trap::IRQ_KBD, kbd_intr) };
+    ioapic::enable(trap::IRQ_KBD, 0);
+}
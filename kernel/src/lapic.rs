@@ -1,4 +1,5 @@
 use super::memory::p2v;
+use super::rtc::CMOS_PORT;
 use utils::prelude::*;
 use utils::x86;
 
@@ -85,6 +86,38 @@ const MASKED: u32 = 0x00010000;
 pub(crate) static mut LAPIC: Option<*mut u32> = None;
 // Initialized in mp::init()
 
+/// Frequency of timer interrupts (Hz), set at build time by $TICK_HZ (default 100).
+/// calibrate() measures the timer, and init() divides it down to this rate.
+pub const TICK_HZ: u32 = include!(concat!(env!("OUT_DIR"), "/tick_hz.rs"));
+
+/// How long to count against the PIT when calibrating (us)
+const CALIBRATE_US: u32 = 10_000;
+
+/// LAPIC timer counts per second (with divide by 1), 0 until calibrated
+static mut TIMER_FREQ: u32 = 0;
+/// TSC cycles per microsecond, 0 until calibrated
+static mut TSC_PER_US: u64 = 0;
+
+/// Measure the speed of the LAPIC timer and of the TSC against the PIT.
+fn calibrate() {
+    use super::trap;
+
+    // one-shot, masked: just count down from the maximum.
+    LapicReg::TDCR.write(X1);
+    LapicReg::TIMER.write(MASKED | (trap::T_IRQ0 + trap::IRQ_TIMER));
+    let tsc_start = x86::rdtsc();
+    LapicReg::TICR.write(u32::MAX);
+    super::pit::delay(CALIBRATE_US);
+    let counted = u32::MAX - LapicReg::TCCR.read();
+    let tsc_end = x86::rdtsc();
+    LapicReg::TICR.write(0);
+
+    unsafe {
+        TIMER_FREQ = (counted as u64 * 1_000_000 / CALIBRATE_US as u64) as u32;
+        TSC_PER_US = (tsc_end - tsc_start) / CALIBRATE_US as u64;
+    }
+}
+
 pub fn init() {
     if unsafe { LAPIC.is_none() } {
         return;
@@ -97,11 +130,14 @@ pub fn init() {
 
     // The timer repeatedly counts down at bus frequency
     // from lapic[TICR] and then issues an interrupt.
-    // If xv6 cared more about precise timekeeping,
-    // TICR would be calibrated using an external time source.
+    // The first CPU measures the bus frequency against the PIT;
+    // all CPUs share the bus, so the others reuse the result.
+    if unsafe { TIMER_FREQ } == 0 {
+        calibrate();
+    }
     LapicReg::TDCR.write(X1);
     LapicReg::TIMER.write(PERIODIC | (trap::T_IRQ0 + trap::IRQ_TIMER));
-    LapicReg::TICR.write(10000000);
+    LapicReg::TICR.write(unsafe { TIMER_FREQ } / TICK_HZ);
 
     // Disable logical interrupt lines.
     LapicReg::LINT0.write(MASKED);
@@ -146,11 +182,18 @@ pub fn eoi() {
 }
 
 /// Spin for a given number of microseconds.
-/// On real hardware would want to tune this dynamically.
-pub fn micro_delay(_us: u32) {}
-
-const CMOS_PORT: u16 = 0x70;
-const CMOS_RETURN: u16 = 0x71;
+/// Uses the TSC once calibrated, and the PIT before that.
+pub fn micro_delay(us: u32) {
+    let tsc_per_us = unsafe { TSC_PER_US };
+    if tsc_per_us == 0 {
+        super::pit::delay(us);
+        return;
+    }
+    let end = x86::rdtsc() + tsc_per_us * us as u64;
+    while x86::rdtsc() < end {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
 
 // Start additional processor running entry code at addr.
 // See Appendix B of MultiProcessor Specification.
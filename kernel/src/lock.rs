@@ -87,6 +87,11 @@ pub mod spin {
             self.lock.acquire();
             SpinMutexGuard { mtx: self }
         }
+        /// Release the lock which has been acquired without a guard
+        /// on this cpu (e.g. by the scheduler on behalf of a new process).
+        pub unsafe fn force_unlock(&self) {
+            self.lock.release()
+        }
     }
     unsafe impl<T: Send> Send for SpinMutex<T> {}
     unsafe impl<T: Send> Sync for SpinMutex<T> {}
@@ -99,7 +104,7 @@ pub mod spin {
             self.mtx.lock.release()
         }
         pub unsafe fn force_locked(&self) {
-            self.mtx.lock.release()
+            self.mtx.lock.acquire()
         }
     }
     use core::ops::{Deref, DerefMut};
@@ -141,10 +146,14 @@ pub mod sleep {
                 pid: u32::MAX,
             }
         }
+        fn chan(&self) -> usize {
+            self as *const _ as usize
+        }
+        #[allow(clippy::while_immutable_condition)] // proc::sleep releases `locked` while sleeping
         pub fn acquire(&self) {
             let mut locked = self.locked.lock();
             while *locked {
-                proc::sleep(&self as *const _ as usize, &locked);
+                proc::sleep(self.chan(), &locked);
             }
             *locked = true;
             // self.pid = todo!();
@@ -152,7 +161,7 @@ pub mod sleep {
         pub fn release(&self) {
             let mut locked = self.locked.lock();
             *locked = false;
-            proc::wakeup(&self as *const _ as usize);
+            proc::wakeup(self.chan());
         }
     }
 
@@ -22,18 +22,24 @@ extern crate rlibc;
 
 #[macro_use]
 mod console;
+mod exec;
 mod fs;
 mod ioapic;
 mod kalloc;
+mod kbd;
 mod lapic;
 mod lock;
 mod memory;
 mod mp;
 mod pic_irq;
+mod pit;
 mod proc;
+mod rtc;
+mod syscall;
 mod trap;
 mod uart;
 mod vm;
+mod vma;
 
 use utils::prelude::*;
 use utils::{assigned_array, x86};
@@ -83,6 +89,7 @@ pub extern "C" fn main() -> ! {
     uart::init();
     proc::init();
     trap::init();
+    syscall::init();
     fs::init();
 
     test_main();
@@ -108,8 +115,10 @@ pub extern "C" fn main() -> ! {
     console::init(); // console hardware
     uart::init(); // serial port
     uart::puts("xv6...\n"); // Announce that we're here.
+    rtc::init(); // boot time
     proc::init(); // process table
     trap::init(); // trap vectors
+    syscall::init(); // system call table
     fs::init(); // ide, buffer cache, inode cache
     start_others(); // start other processors
 
@@ -156,7 +165,7 @@ fn start_others() {
         unsafe {
             let code = code.mut_ptr();
             *code.sub(1) = stack.add(KSTACKSIZE);
-            *code.sub(2) = core::mem::transmute(mp_enter as extern "C" fn());
+            *code.sub(2) = mp_enter as usize as *mut c_void;
             *code.sub(3) = v2p(VAddr::from(entry_page_dir.as_ptr())).cast().mut_ptr();
         }
 
@@ -174,6 +183,7 @@ fn start_others() {
 fn panic(info: &core::panic::PanicInfo) -> ! {
     x86::cli(); // stop interruption
     println!(console::print_color::LIGHT_RED; "{}", info);
+    uart::flush();
 
     // exit immediately if we are under test mode
     #[cfg(test)]
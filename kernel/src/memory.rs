@@ -115,6 +115,9 @@ pub mod pg_dir {
         pub fn new(page_addr: PAddr<super::Page>, flags: u32) -> Self {
             Self(page_addr.raw() as u32 | flags & TAB_ENT_FLAG_MASK)
         }
+        pub const fn zero() -> Self {
+            Self(0)
+        }
         #[inline]
         pub fn set_flags(&mut self, flags: u32) {
             self.0 |= flags & TAB_ENT_FLAG_MASK;
@@ -135,6 +138,11 @@ pub mod pg_dir {
 
     #[allow(dead_code)]
     pub mod ent_flag {
+        /// Available to software: the page is shared copy-on-write.
+        /// Such a page is mapped read-only and copied on the first write.
+        pub const COW: u32 = 0b001000000000;
+        /// Set by the processor when the page is written to.
+        pub const DIRTY: u32 = 0b000001000000;
         /// If the bit is set, then pages are 4 MiB in size. Otherwise, they are 4 KiB.
         /// Please note that 4-MiB pages require PSE to be enabled.
         pub const PAGE_SIZE_4MIB: u32 = 0b000010000000;
@@ -165,8 +173,10 @@ pub mod seg {
     pub const SEG_UDATA: usize = 4;
     /// this process's task state
     pub const SEG_TSS: usize = 5;
+    /// task state of the double fault handler
+    pub const SEG_DFTSS: usize = 6;
     /// CPU.gdt[SegDesc; NSEGS]; holds the above segments
-    pub const NSEGS: usize = 6;
+    pub const NSEGS: usize = 7;
 
     /// GDT initial value
     pub const GDT_ZERO: [SegDesc; NSEGS] = [
@@ -176,6 +186,7 @@ pub mod seg {
         SegDesc::new(),
         SegDesc::new(),
         SegDesc::new(),
+        SegDesc::new(),
     ];
 
     pub mod seg_type {
@@ -190,6 +201,8 @@ pub mod seg {
 
         // System segment type bits
 
+        /// Task Gate
+        pub const STS_TG: u8 = 0x5;
         /// Available 32-bit TSS
         pub const STS_T32A: u8 = 0x9;
         /// 32-bit Interrupt Gate
@@ -319,6 +332,16 @@ pub mod gate {
                 .set_selector(selector)
                 .set_type_attribute(attr_ty);
         }
+        /// Set up a task gate, which switches to the task
+        /// whose task state segment the selector refers to.
+        pub fn set_task(&mut self, selector: u16) {
+            use super::seg::seg_type::STS_TG;
+
+            let present = 1;
+            *self = Self::new()
+                .set_selector(selector)
+                .set_type_attribute((present << 7) | STS_TG);
+        }
     }
 }
 
@@ -339,6 +362,8 @@ pub const KERNLINK: VAddr<Page> = unsafe { VAddr::from_raw_unchecked(0x80100000)
 pub const EXTMEM: PAddr<Page> = unsafe { PAddr::from_raw_unchecked(0x100000) };
 /// Top physical memory
 pub const PHYSTOP: PAddr<Page> = unsafe { PAddr::from_raw_unchecked(0xE000000) };
+/// Kernel stacks are mapped from here up, each above an unmapped guard page
+pub const KSTACKBASE: VAddr<Page> = unsafe { VAddr::from_raw_unchecked(0xFD000000) };
 /// Other devices are at high addresses
 pub const DEVSPACE: VAddr<Page> = unsafe { VAddr::from_raw_unchecked(0xFE000000) };
 
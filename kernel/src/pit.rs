@@ -0,0 +1,56 @@
+//! Intel 8253/8254 programmable interval timer.
+//! Its input clock has a known frequency,
+//! so it serves as the reference for calibrating the other timers.
+
+use utils::x86;
+
+/// Input clock frequency (Hz)
+const PIT_FREQ: u32 = 1_193_182;
+
+/// Channel 2 data port
+const CH2_DATA: u16 = 0x42;
+/// Mode/command register (W)
+const COMMAND: u16 = 0x43;
+/// Keyboard controller port B: channel 2 gate and output
+const PORT_B: u16 = 0x61;
+/// Gate input of channel 2
+const PORT_B_GATE: u8 = 0x01;
+/// Speaker data enable
+const PORT_B_SPEAKER: u8 = 0x02;
+/// Output of channel 2
+const PORT_B_OUT: u8 = 0x20;
+
+/// The longest wait a single countdown can time (us)
+const MAX_CHUNK_US: u32 = 50_000;
+
+/// Spin for `us` microseconds, timed by channel 2.
+/// Does not use interrupts, so it works before anything else is set up.
+pub fn delay(us: u32) {
+    let mut left = us;
+    while left > 0 {
+        let chunk = u32::min(left, MAX_CHUNK_US);
+        countdown(chunk);
+        left -= chunk;
+    }
+}
+
+/// Count down `us` (<= MAX_CHUNK_US) microseconds on channel 2
+/// and wait until it reaches zero.
+fn countdown(us: u32) {
+    let count = (PIT_FREQ as u64 * us as u64 / 1_000_000).max(1) as u16;
+
+    // Gate low stops the counter; keep the speaker off.
+    let b = x86::inb(PORT_B) & !(PORT_B_GATE | PORT_B_SPEAKER);
+    x86::outb(PORT_B, b);
+
+    // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+    x86::outb(COMMAND, 0b1011_0000);
+    x86::outb(CH2_DATA, (count & 0xFF) as u8);
+    x86::outb(CH2_DATA, (count >> 8) as u8);
+
+    // Raising the gate starts counting; the output goes high at zero.
+    x86::outb(PORT_B, b | PORT_B_GATE);
+    while x86::inb(PORT_B) & PORT_B_OUT == 0 {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
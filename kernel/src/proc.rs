@@ -1,8 +1,9 @@
-use super::fs::inode;
+use super::fs::{file, inode};
 use super::lock::spin::{SpinMutex, SpinMutexGuard};
-use super::memory::{pg_dir, seg, PAGE_SIZE};
+use super::memory::{pg_dir, seg, KSTACKSIZE, PAGE_SIZE};
 use super::trap;
 use super::vm;
+use super::vma::{self, Vma, VmaKind};
 use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, VecDeque};
 use alloc::sync::Arc;
@@ -112,6 +113,10 @@ pub struct Cpu {
     pub scheduler: *const Context,
     /// Used by x86 to find stack for interrupt
     pub task_state: TaskState,
+    /// Task the double fault handler runs in
+    pub df_task_state: TaskState,
+    /// Stack of the double fault handler
+    pub df_stack: [u8; KSTACKSIZE],
     /// x86 global descriptor table
     pub gdt: [seg::SegDesc; seg::NSEGS],
     /// Depth of push_cli nesting.
@@ -136,6 +141,8 @@ impl CpuShared {
             private: RefCell::new(Cpu {
                 scheduler: core::ptr::null(),
                 task_state: TaskState::zero(),
+                df_task_state: TaskState::zero(),
+                df_stack: [0; KSTACKSIZE],
                 gdt: seg::GDT_ZERO,
                 num_cli: 0,
                 int_enabled: false,
@@ -200,7 +207,12 @@ pub fn my_cpu() -> RefMut<'static, Cpu> {
 /// Disable interrupts so that we are not rescheduled
 /// while reading proc from the cpu structure
 pub fn my_proc() -> ProcessRef {
-    super::lock::cli(|| my_cpu().current_proc.clone().unwrap())
+    try_my_proc().unwrap()
+}
+
+/// Same as my_proc(), but returns None if no process is running on this cpu.
+pub fn try_my_proc() -> Option<ProcessRef> {
+    super::lock::cli(|| my_cpu().current_proc.clone())
 }
 
 /// Saved registers for kernel context switches.
@@ -234,6 +246,8 @@ impl Context {
 }
 
 const MAX_NPROC: usize = 64;
+/// open files per process
+pub const N_OFILE: usize = 16;
 
 #[derive(Debug, Eq, PartialEq)]
 enum ProcessState {
@@ -245,14 +259,19 @@ enum ProcessState {
     Zombie,
 }
 pub struct Process {
-    state: ProcessState,                    // Process state
-    pub size: usize,                        // Size of process memory (bytes)
-    pub pg_dir: Box<pg_dir::PageDirectory>, // Page table
-    pub kernel_stack: *mut u8,              // Bottom of kernel stack for this process
-    pub pid: u32,                           // Process ID
-    pub trap_frame: *mut trap::TrapFrame,   // Trap frame for current syscall
-    pub context: *mut Context,              // swtch() here to run process
-    pub cwd: Option<inode::InodeRef>,       // Current directory
+    state: ProcessState,                         // Process state
+    pub size: usize,                             // Size of process memory (bytes)
+    pub pg_dir: Box<pg_dir::PageDirectory>,      // Page table
+    pub vmas: Vec<Vma>,                          // Memory areas, sorted by address
+    pub kernel_stack: *mut u8,                   // Bottom of kernel stack for this process
+    pub pid: u32,                                // Process ID
+    pub trap_frame: *mut trap::TrapFrame,        // Trap frame for current syscall
+    pub context: *mut Context,                   // swtch() here to run process
+    pub files: [Option<file::FileRef>; N_OFILE], // Open files
+    pub cwd: Option<inode::InodeRef>,            // Current directory
+    pub parent: Option<ProcessRef>,              // Parent process
+    pub killed: bool,                            // If true, have been killed
+    pub exit_status: i32,                        // Exit status to be returned to parent's wait
 
     pub name: [u8; 16], // Process name (debugging)
 }
@@ -262,11 +281,16 @@ impl Process {
             state: ProcessState::Unused,
             size: 0,
             pg_dir: pg_dir::PageDirectory::zero_boxed(),
+            vmas: Vec::new(),
             kernel_stack: core::ptr::null_mut(),
             pid: u32::MAX,
             trap_frame: core::ptr::null_mut(),
             context: core::ptr::null_mut(),
+            files: Default::default(),
             cwd: None,
+            parent: None,
+            killed: false,
+            exit_status: 0,
 
             name: [0; 16],
         }
@@ -274,6 +298,11 @@ impl Process {
     pub fn is_valid(&self) -> bool {
         !self.kernel_stack.is_null()
     }
+    /// Free the kernel stack. The process must not be running on it.
+    fn free_kernel_stack(&mut self) {
+        vm::kstack_free(self.kernel_stack);
+        self.kernel_stack = core::ptr::null_mut();
+    }
 }
 impl core::fmt::Debug for Process {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -299,7 +328,20 @@ unsafe impl Send for Process {}
 
 pub type ProcessRef = Arc<SpinMutex<Process>>;
 
+/// Whether p is a child of parent.
+fn is_child(p: &Process, parent: &ProcessRef) -> bool {
+    p.parent
+        .as_ref()
+        .map_or(false, |pp| Arc::ptr_eq(pp, parent))
+}
+
+/// Channel on which a process waits for its children.
+fn wait_chan(p: &ProcessRef) -> usize {
+    Arc::as_ptr(p) as usize
+}
+
 struct ProcessTable {
+    procs: BTreeMap<u32, ProcessRef>,
     sleeping: BTreeMap<usize, Vec<ProcessRef>>,
     runnable: VecDeque<ProcessRef>,
     init: Option<ProcessRef>,
@@ -308,6 +350,7 @@ struct ProcessTable {
 impl ProcessTable {
     pub fn new() -> Self {
         Self {
+            procs: BTreeMap::new(),
             sleeping: BTreeMap::new(),
             runnable: VecDeque::new(),
             init: None,
@@ -329,13 +372,17 @@ impl ProcessTable {
     }
 
     /// Create new process.
-    pub fn alloc_proc(&mut self) -> ProcessRef {
+    /// Returns None if there are too many processes or no kernel stack is left.
+    pub fn alloc_proc(&mut self) -> Option<ProcessRef> {
+        if self.procs.len() >= MAX_NPROC {
+            return None;
+        }
         let mut p = Process::new();
         p.state = ProcessState::Embryo;
-        p.pid = self.take_next_pid();
 
         // Allocate kernel stack.
-        p.kernel_stack = super::kalloc::kalloc().unwrap().as_ptr() as *mut u8;
+        p.kernel_stack = vm::kstack_alloc()?;
+        p.pid = self.take_next_pid();
         unsafe {
             let sp = p.kernel_stack.add(super::memory::KSTACKSIZE);
             use core::mem::size_of;
@@ -362,19 +409,31 @@ impl ProcessTable {
                 *p.context = ctx;
             }
         }
-        Arc::new(SpinMutex::new("process", p))
+        let pid = p.pid;
+        let p = Arc::new(SpinMutex::new("process", p));
+        self.procs.insert(pid, p.clone());
+        Some(p)
     }
 
     /// Set up the first user process.
     fn user_init(&mut self) {
         const INIT_CODE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/init.bin"));
 
-        let p = self.alloc_proc();
+        let p = self.alloc_proc().expect("user_init: alloc_proc");
         {
             let mut p = p.lock();
             p.pg_dir = vm::setup_kvm().expect("user_init: out of memory");
             vm::uvm::init(&mut p.pg_dir, INIT_CODE);
             p.size = PAGE_SIZE;
+            // The code and its data share the first page.
+            let rw = vma::PROT_READ | vma::PROT_WRITE;
+            p.vmas = alloc::vec![
+                Vma::anonymous(VmaKind::Data, 0, PAGE_SIZE, rw | vma::PROT_EXEC),
+                Vma::anonymous(VmaKind::Heap, PAGE_SIZE, PAGE_SIZE, rw),
+            ];
+            let p = &mut *p;
+            let sp =
+                vma::setup_stack(&mut p.pg_dir, &mut p.vmas).expect("user_init: out of memory");
             {
                 let tf = unsafe { &mut *p.trap_frame };
                 tf.cs = (seg::SEG_UCODE << 3) as u16 | seg::dpl::USER as u16;
@@ -383,7 +442,7 @@ impl ProcessTable {
                 tf.es = udata;
                 tf.ss = udata;
                 tf.eflags = x86::eflags::FL_IF;
-                tf.esp = PAGE_SIZE;
+                tf.esp = sp;
                 tf.eip = 0; // begin of init
             }
             let name = b"init\0";
@@ -396,11 +455,28 @@ impl ProcessTable {
         self.put(p);
     }
 
+    /// Put p to sleep on chan. The caller must call sched() afterwards.
     fn sleep(&mut self, chan: usize, p: &ProcessRef) {
-        if !self.sleeping.contains_key(&chan) {
-            self.sleeping.insert(chan, Vec::new());
+        p.lock().state = ProcessState::Sleeping;
+        self.sleeping.entry(chan).or_default().push(p.clone());
+    }
+
+    /// Make a sleeping process p runnable regardless of its channel.
+    fn wake_process(&mut self, p: &ProcessRef) {
+        let chan = self
+            .sleeping
+            .iter()
+            .find(|(_, sleeping)| sleeping.iter().any(|q| Arc::ptr_eq(q, p)))
+            .map(|(chan, _)| *chan);
+        if let Some(chan) = chan {
+            let sleeping = self.sleeping.get_mut(&chan).unwrap();
+            sleeping.retain(|q| !Arc::ptr_eq(q, p));
+            if sleeping.is_empty() {
+                self.sleeping.remove(&chan);
+            }
         }
-        self.sleeping.get_mut(&chan).unwrap().push(p.clone());
+        p.lock().state = ProcessState::Runnable;
+        self.put(p.clone());
     }
 
     /// Wake up all processes sleeping on chan.
@@ -418,11 +494,24 @@ lazy_static! {
     static ref PROC_TABLE: SpinMutex<ProcessTable> = SpinMutex::new("ptable", ProcessTable::new());
 }
 
+/// Atomically release the lock of guard and sleep on chan.
+/// Reacquires the lock when awakened.
 pub fn sleep<'g, 'lk: 'g, T>(chan: usize, guard: &'g SpinMutexGuard<'lk, T>) {
     let p = my_proc();
-    PROC_TABLE.lock().sleep(chan, &p);
+
+    // Must acquire PROC_TABLE lock in order to change p.state and then call sched.
+    // Once we hold PROC_TABLE lock, we can be guaranteed that we won't miss
+    // any wakeup (wakeup runs with PROC_TABLE locked),
+    // so it's okay to release the lock of guard.
+    let mut table = PROC_TABLE.lock();
     unsafe { guard.force_unlocked() };
+
+    // Go to sleep.
+    table.sleep(chan, &p);
     sched();
+
+    // Reacquire original lock.
+    drop(table);
     unsafe { guard.force_locked() };
 }
 
@@ -430,12 +519,16 @@ pub fn wakeup(chan: usize) {
     PROC_TABLE.lock().wakeup(chan);
 }
 
-/// Enter scheduler.
+/// Enter scheduler. Must hold only PROC_TABLE lock
+/// and have changed the state of the current process.
+/// Saves and restores int_enabled because int_enabled is a property of this
+/// kernel thread, not this CPU. It should be my_proc().int_enabled, but that would
+/// break in the few places where a lock is held but there's no process.
 fn sched() {
     let p = my_proc();
 
-    assert!(p.lock().state != ProcessState::Running, "sched: running");
     assert!(my_cpu().num_cli == 1, "sched: locks");
+    assert!(p.lock().state != ProcessState::Running, "sched: running");
     assert!(
         x86::read_eflags() & x86::eflags::FL_IF == 0,
         "sched: interruptible"
@@ -444,12 +537,231 @@ fn sched() {
     let int_ena = my_cpu().int_enabled;
     {
         let sched_ctx = my_cpu().scheduler;
-        let proc_ctx = &mut p.lock().context as *mut *mut Context as *mut *const Context;
+        let proc_ctx = {
+            let mut p = p.lock();
+            &mut p.context as *mut *mut Context as *mut *const Context
+        };
+        // The process is kept alive by PROC_TABLE.procs. Don't hold the reference
+        // across the switch, since a zombie never comes back to drop it.
+        drop(p);
         unsafe { switch(proc_ctx, sched_ctx) };
     }
     my_cpu().int_enabled = int_ena;
 }
 
+/// Give up the CPU for one scheduling round.
+pub fn yield_() {
+    let mut table = PROC_TABLE.lock();
+    let p = my_proc();
+    p.lock().state = ProcessState::Runnable;
+    table.put(p);
+    sched();
+    drop(table);
+}
+
+/// Grow or shrink the current process's memory by n bytes.
+/// Growing only extends the heap area: the page fault handler
+/// allocates zeroed pages when they are first touched.
+/// Returns the old size, or None if the new size is out of range.
+pub fn grow(n: i32) -> Option<u32> {
+    let p = my_proc();
+    let mut p = p.lock();
+    let p = &mut *p;
+    let old_sz = p.size;
+    let new_sz = if n >= 0 {
+        old_sz.checked_add(n as usize)?
+    } else {
+        old_sz.checked_sub(-(n as i64) as usize)?
+    };
+    let heap = p.vmas.iter_mut().find(|v| v.kind == VmaKind::Heap)?;
+    if new_sz < heap.start || new_sz > vma::HEAP_TOP {
+        return None;
+    }
+    vm::uvm::dealloc(&mut p.pg_dir, old_sz, new_sz);
+    heap.end = (new_sz + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    p.size = new_sz;
+    Some(old_sz as u32)
+}
+
+/// Create a new process copying the current one as the parent.
+/// Sets up the child's trap frame so that it returns from the system call with 0.
+/// Returns the pid of the child, or None if out of memory or processes.
+pub fn fork() -> Option<u32> {
+    let cur_ref = my_proc();
+    let np = PROC_TABLE.lock().alloc_proc()?;
+    let pid = {
+        let mut cur_guard = cur_ref.lock();
+        let cur = &mut *cur_guard;
+        let mut child = np.lock();
+
+        // Copy process state from cur.
+        let size = cur.size;
+        child.pg_dir = match vma::copy(&mut cur.pg_dir, &cur.vmas) {
+            Some(pg_dir) => pg_dir,
+            None => {
+                child.free_kernel_stack();
+                child.state = ProcessState::Unused;
+                let pid = child.pid;
+                drop(child);
+                drop(cur_guard);
+                PROC_TABLE.lock().procs.remove(&pid);
+                return None;
+            }
+        };
+        child.size = size;
+        child.vmas = cur.vmas.clone();
+        unsafe {
+            core::ptr::copy_nonoverlapping(cur.trap_frame, child.trap_frame, 1);
+            // Clear %eax so that fork returns 0 in the child.
+            (*child.trap_frame).eax = 0;
+        }
+        child.cwd = cur.cwd.clone();
+        child.name = cur.name;
+        child.files = cur.files.clone();
+        child.parent = Some(cur_ref.clone());
+
+        child.state = ProcessState::Runnable;
+        child.pid
+    };
+    PROC_TABLE.lock().put(np);
+    Some(pid)
+}
+
+/// Exit the current process. Does not return.
+/// An exited process remains in the zombie state
+/// until its parent calls wait() to find out it exited.
+pub fn exit(status: i32) -> ! {
+    let cur = my_proc();
+    let init = PROC_TABLE.lock().init.clone().unwrap();
+    if Arc::ptr_eq(&cur, &init) {
+        panic!("init exiting");
+    }
+
+    // Unmap the memory areas, writing back shared files.
+    let removed = {
+        let mut cur = cur.lock();
+        let cur = &mut *cur;
+        vma::unmap(&mut cur.pg_dir, &mut cur.vmas, 0, vma::MMAP_TOP)
+    };
+    vma::release(removed);
+
+    // Close all open files and release the current directory
+    // outside of the spin lock since dropping an inode may sleep.
+    let files = core::mem::take(&mut cur.lock().files);
+    drop(files);
+    let cwd = cur.lock().cwd.take();
+    {
+        let _tx = super::fs::log::begin_op();
+        drop(cwd);
+    }
+
+    let mut table = PROC_TABLE.lock();
+
+    // Parent might be sleeping in wait().
+    let parent = cur.lock().parent.clone().unwrap();
+    table.wakeup(wait_chan(&parent));
+
+    // Pass abandoned children to init.
+    let mut wake_init = false;
+    for p in table.procs.values() {
+        if Arc::ptr_eq(p, &cur) {
+            continue;
+        }
+        let mut p = p.lock();
+        if is_child(&p, &cur) {
+            p.parent = Some(init.clone());
+            if p.state == ProcessState::Zombie {
+                wake_init = true;
+            }
+        }
+    }
+    if wake_init {
+        table.wakeup(wait_chan(&init));
+    }
+
+    // Jump into the scheduler, never to return.
+    {
+        let mut cur = cur.lock();
+        cur.exit_status = status;
+        cur.state = ProcessState::Zombie;
+    }
+    drop(parent);
+    drop(init);
+    drop(cur);
+    sched();
+    panic!("zombie exit");
+}
+
+/// Wait for a child process to exit and return its pid and exit status.
+/// Return None if this process has no children.
+pub fn wait() -> Option<(u32, i32)> {
+    let cur = my_proc();
+    let mut table = PROC_TABLE.lock();
+    loop {
+        // Scan through table looking for exited children.
+        let mut have_kids = false;
+        let mut zombie = None;
+        for (pid, p) in table.procs.iter() {
+            let p = p.lock();
+            if !is_child(&p, &cur) {
+                continue;
+            }
+            have_kids = true;
+            if p.state == ProcessState::Zombie {
+                zombie = Some(*pid);
+                break;
+            }
+        }
+
+        if let Some(pid) = zombie {
+            // Found one.
+            let p = table.procs.remove(&pid).unwrap();
+            let mut p = p.lock();
+            p.free_kernel_stack();
+            let pg_dir = core::mem::replace(&mut p.pg_dir, pg_dir::PageDirectory::zero_boxed());
+            vm::free_vm(pg_dir);
+            p.parent = None;
+            p.state = ProcessState::Unused;
+            return Some((pid, p.exit_status));
+        }
+
+        // No point waiting if we don't have any children.
+        if !have_kids || cur.lock().killed {
+            return None;
+        }
+
+        // Wait for children to exit. (See wakeup call in exit.)
+        table.sleep(wait_chan(&cur), &cur);
+        sched();
+    }
+}
+
+/// Kill the process with the given pid.
+/// Process won't exit until it returns to user space (see trap()).
+/// Returns false if there is no such process.
+pub fn kill(pid: u32) -> bool {
+    let mut table = PROC_TABLE.lock();
+    let p = match table.procs.get(&pid) {
+        Some(p) => p.clone(),
+        None => return false,
+    };
+    let sleeping = {
+        let mut p = p.lock();
+        p.killed = true;
+        p.state == ProcessState::Sleeping
+    };
+    // Wake process from sleep if necessary.
+    if sleeping {
+        table.wake_process(&p);
+    }
+    true
+}
+
+/// Whether the current process has been killed.
+pub fn killed() -> bool {
+    my_proc().lock().killed
+}
+
 pub fn init() {
     lazy_static::initialize(&PROC_TABLE);
 }
@@ -498,28 +810,32 @@ switch:
 pub fn scheduler() -> ! {
     println!(super::console::print_color::CYAN; "[cpu:{}] scheduler start", my_cpu_id());
 
-    use super::lock::cli;
-
-    // Enable interrupts on this processor.
-    x86::sti();
-
     loop {
-        cli(|| {
-            my_cpu().current_proc = None;
-        });
-        if let Some(p) = PROC_TABLE.lock().get_runnable() {
-            cli(|| {
-                my_cpu().current_proc = Some(p.clone());
-            });
+        // Enable interrupts on this processor.
+        x86::sti();
+
+        let mut table = PROC_TABLE.lock();
+        if let Some(p) = table.get_runnable() {
+            // Switch to chosen process. It is the process's job
+            // to release PROC_TABLE lock and then reacquire it
+            // before jumping back to us.
+            my_cpu().current_proc = Some(p.clone());
             vm::uvm::switch(&p);
-            p.lock().state = ProcessState::Running;
+            let proc_ctx = {
+                let mut p = p.lock();
+                p.state = ProcessState::Running;
+                p.context
+            };
 
-            // switching
-            let sched_ctx = cli(|| &mut my_cpu().scheduler as *mut _);
-            unsafe { switch(sched_ctx, p.lock().context) };
+            let sched_ctx = &mut my_cpu().scheduler as *mut _;
+            unsafe { switch(sched_ctx, proc_ctx) };
 
             vm::switch_kvm();
+
+            // Process is done running for now.
+            my_cpu().current_proc = None;
         }
+        drop(table);
     }
 }
 
@@ -527,12 +843,15 @@ pub fn scheduler() -> ! {
 /// will switch here. "Return" to user space.
 #[no_mangle]
 extern "C" fn forkret() {
+    // Still holding PROC_TABLE lock from scheduler.
+    unsafe { PROC_TABLE.force_unlock() };
+
     static FIRST_TIME: AtomicBool = AtomicBool::new(true);
     if FIRST_TIME.compare_and_swap(true, false, Ordering::SeqCst) {
         // Some initialization functions must be run in the context
         // of a regular process (e.g., they call sleep), and thus cannot
         // be run from main().
-        todo!()
+        super::fs::mount(super::fs::ROOT_DEV);
     }
     // Return to "caller", actually trapret (see alloc_proc).
 }
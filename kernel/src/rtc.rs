@@ -0,0 +1,193 @@
+//! CMOS real-time clock
+//!
+//! The RTC is read once at boot. After that, time advances with the
+//! timer tick, which gives a monotonic clock (time since boot)
+//! and a real-time clock (boot time + time since boot).
+
+use super::lapic::TICK_HZ;
+use super::trap::TICKS;
+use utils::x86;
+
+pub const CMOS_PORT: u16 = 0x70;
+pub const CMOS_RETURN: u16 = 0x71;
+
+// CMOS registers
+const SECS: u8 = 0x00;
+const MINS: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const CENTURY: u8 = 0x32;
+const STAT_A: u8 = 0x0A;
+const STAT_B: u8 = 0x0B;
+
+/// Update in progress
+const STAT_A_UIP: u8 = 1 << 7;
+/// 24-hour mode (otherwise 12-hour)
+const STAT_B_24H: u8 = 1 << 1;
+/// Binary mode (otherwise BCD)
+const STAT_B_BIN: u8 = 1 << 2;
+/// PM flag of the hour register in 12-hour mode
+const HOURS_PM: u8 = 1 << 7;
+
+pub const CLOCK_REALTIME: u32 = 0;
+pub const CLOCK_MONOTONIC: u32 = 1;
+
+/// A point in time, as passed to user space.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct TimeSpec {
+    pub sec: u64,
+    pub nsec: u32,
+}
+
+/// Wall-clock date and time, in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcDate {
+    pub second: u32,
+    pub minute: u32,
+    pub hour: u32,
+    pub day: u32,
+    pub month: u32,
+    pub year: u32,
+}
+
+impl RtcDate {
+    /// Seconds since 1970-01-01 00:00:00 UTC
+    pub fn to_unix(&self) -> u64 {
+        // from: http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+        let (y, m, d) = (self.year as i64, self.month as i64, self.day as i64);
+        let y = if m <= 2 { y - 1 } else { y };
+        let era = if y >= 0 { y } else { y - 399 } / 400;
+        let yoe = y - era * 400;
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+}
+
+/// Real time at boot (seconds since the epoch)
+static mut BOOT_TIME: u64 = 0;
+
+fn cmos_read(reg: u8) -> u8 {
+    x86::outb(CMOS_PORT, reg);
+    x86::inb(CMOS_RETURN)
+}
+
+fn bcd_to_bin(v: u8) -> u8 {
+    (v & 0x0F) + (v >> 4) * 10
+}
+
+/// Raw register values: sec, min, hour, day, month, year, century
+fn read_regs() -> [u8; 7] {
+    // Don't read while the RTC is updating the registers.
+    while cmos_read(STAT_A) & STAT_A_UIP != 0 {}
+    [
+        cmos_read(SECS),
+        cmos_read(MINS),
+        cmos_read(HOURS),
+        cmos_read(DAY),
+        cmos_read(MONTH),
+        cmos_read(YEAR),
+        cmos_read(CENTURY),
+    ]
+}
+
+/// Read the current date from the CMOS.
+pub fn read_date() -> RtcDate {
+    // An update may start right after the UIP check,
+    // so read until two consecutive reads agree.
+    let mut regs = read_regs();
+    loop {
+        let again = read_regs();
+        if again == regs {
+            break;
+        }
+        regs = again;
+    }
+
+    let stat_b = cmos_read(STAT_B);
+    let bin = |v: u8| {
+        if stat_b & STAT_B_BIN != 0 {
+            v
+        } else {
+            bcd_to_bin(v)
+        }
+    };
+
+    let [sec, min, hour, day, month, year, century] = regs;
+    let pm = hour & HOURS_PM != 0;
+    let mut hour = bin(hour & !HOURS_PM) as u32;
+    if stat_b & STAT_B_24H == 0 {
+        // 12 AM is 0:00 and 12 PM is 12:00
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    let century = match bin(century) {
+        c @ 19..=99 => c as u32,
+        // no century register
+        _ => 20,
+    };
+
+    RtcDate {
+        second: bin(sec) as u32,
+        minute: bin(min) as u32,
+        hour,
+        day: bin(day) as u32,
+        month: bin(month) as u32,
+        year: century * 100 + bin(year) as u32,
+    }
+}
+
+/// Record the boot time. Must be called before the timer starts ticking.
+pub fn init() {
+    let date = read_date();
+    unsafe { BOOT_TIME = date.to_unix() };
+}
+
+/// Time elapsed since boot
+pub fn monotonic() -> TimeSpec {
+    let ticks = *TICKS.lock();
+    TimeSpec {
+        sec: (ticks / TICK_HZ) as u64,
+        nsec: (ticks % TICK_HZ) * (1_000_000_000 / TICK_HZ),
+    }
+}
+
+/// Current wall-clock time
+pub fn realtime() -> TimeSpec {
+    let t = monotonic();
+    TimeSpec {
+        sec: unsafe { BOOT_TIME } + t.sec,
+        ..t
+    }
+}
+
+/// Seconds since the epoch
+pub fn time() -> u64 {
+    realtime().sec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_to_unix() {
+        let date = |year, month, day, hour, minute, second| RtcDate {
+            second,
+            minute,
+            hour,
+            day,
+            month,
+            year,
+        };
+        assert_eq!(date(1970, 1, 1, 0, 0, 0).to_unix(), 0);
+        assert_eq!(date(2000, 3, 1, 0, 0, 0).to_unix(), 951868800);
+        assert_eq!(date(2021, 1, 2, 3, 4, 5).to_unix(), 1609556645);
+    }
+}
@@ -0,0 +1,288 @@
+//! System call dispatching.
+//!
+//! User code makes a system call with INT T_SYSCALL.
+//! System call number in %eax.
+//! Arguments on the stack, from the user call to the C
+//! library system call function. The saved user %esp points
+//! to a saved program counter, and then the first argument.
+
+mod sysfile;
+mod sysproc;
+
+use crate::proc::my_proc;
+use crate::trap::{self, TrapFrame};
+use crate::vma;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use utils::assigned_array;
+
+// System call numbers
+pub const SYS_FORK: usize = 1;
+pub const SYS_EXIT: usize = 2;
+pub const SYS_WAIT: usize = 3;
+pub const SYS_PIPE: usize = 4;
+pub const SYS_READ: usize = 5;
+pub const SYS_KILL: usize = 6;
+pub const SYS_EXEC: usize = 7;
+pub const SYS_FSTAT: usize = 8;
+pub const SYS_CHDIR: usize = 9;
+pub const SYS_DUP: usize = 10;
+pub const SYS_GETPID: usize = 11;
+pub const SYS_SBRK: usize = 12;
+pub const SYS_SLEEP: usize = 13;
+pub const SYS_UPTIME: usize = 14;
+pub const SYS_OPEN: usize = 15;
+pub const SYS_WRITE: usize = 16;
+pub const SYS_MKNOD: usize = 17;
+pub const SYS_UNLINK: usize = 18;
+pub const SYS_LINK: usize = 19;
+pub const SYS_MKDIR: usize = 20;
+pub const SYS_CLOSE: usize = 21;
+pub const SYS_TIME: usize = 22;
+pub const SYS_CLOCK_GETTIME: usize = 23;
+pub const SYS_MMAP: usize = 24;
+pub const SYS_MUNMAP: usize = 25;
+pub const SYS_MPROTECT: usize = 26;
+
+/// Maximum length of a path, including the nul
+pub const MAX_PATH: usize = 128;
+
+/// Upper bound of system call numbers
+const N_SYSCALLS: usize = 32;
+
+type SyscallFn = fn() -> Result<u32>;
+
+static SYSCALLS: [Option<SyscallFn>; N_SYSCALLS] = assigned_array![
+    None; N_SYSCALLS;
+    [SYS_FORK] = Some(sysproc::sys_fork as SyscallFn),
+    [SYS_EXIT] = Some(sysproc::sys_exit as SyscallFn),
+    [SYS_WAIT] = Some(sysproc::sys_wait as SyscallFn),
+    [SYS_PIPE] = Some(sysfile::sys_pipe as SyscallFn),
+    [SYS_READ] = Some(sysfile::sys_read as SyscallFn),
+    [SYS_KILL] = Some(sysproc::sys_kill as SyscallFn),
+    [SYS_EXEC] = Some(sysfile::sys_exec as SyscallFn),
+    [SYS_FSTAT] = Some(sysfile::sys_fstat as SyscallFn),
+    [SYS_CHDIR] = Some(sysfile::sys_chdir as SyscallFn),
+    [SYS_DUP] = Some(sysfile::sys_dup as SyscallFn),
+    [SYS_GETPID] = Some(sysproc::sys_getpid as SyscallFn),
+    [SYS_SBRK] = Some(sysproc::sys_sbrk as SyscallFn),
+    [SYS_SLEEP] = Some(sysproc::sys_sleep as SyscallFn),
+    [SYS_UPTIME] = Some(sysproc::sys_uptime as SyscallFn),
+    [SYS_OPEN] = Some(sysfile::sys_open as SyscallFn),
+    [SYS_WRITE] = Some(sysfile::sys_write as SyscallFn),
+    [SYS_MKNOD] = Some(sysfile::sys_mknod as SyscallFn),
+    [SYS_UNLINK] = Some(sysfile::sys_unlink as SyscallFn),
+    [SYS_LINK] = Some(sysfile::sys_link as SyscallFn),
+    [SYS_MKDIR] = Some(sysfile::sys_mkdir as SyscallFn),
+    [SYS_CLOSE] = Some(sysfile::sys_close as SyscallFn),
+    [SYS_TIME] = Some(sysproc::sys_time as SyscallFn),
+    [SYS_CLOCK_GETTIME] = Some(sysproc::sys_clock_gettime as SyscallFn),
+    [SYS_MMAP] = Some(sysfile::sys_mmap as SyscallFn),
+    [SYS_MUNMAP] = Some(sysfile::sys_munmap as SyscallFn),
+    [SYS_MPROTECT] = Some(sysfile::sys_mprotect as SyscallFn)
+];
+
+/// Errors returned to user space as negative errno values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    /// Operation not permitted
+    NotPermitted,
+    /// No such file or directory
+    NoEntry,
+    /// No such process
+    NoProcess,
+    /// Interrupted system call
+    Interrupted,
+    /// I/O error
+    Io,
+    /// Argument list too long
+    TooBig,
+    /// Exec format error
+    NoExec,
+    /// Bad file descriptor
+    BadFd,
+    /// No child processes
+    NoChild,
+    /// Out of memory
+    NoMemory,
+    /// Permission denied
+    PermissionDenied,
+    /// Bad address
+    BadAddress,
+    /// File exists
+    Exists,
+    /// Not a directory
+    NotDir,
+    /// Is a directory
+    IsDir,
+    /// Invalid argument
+    InvalidArg,
+    /// Too many open files
+    TooManyFiles,
+    /// No space left on device
+    NoSpace,
+    /// Broken pipe
+    BrokenPipe,
+    /// Function not implemented
+    NoSys,
+    /// Directory not empty
+    NotEmpty,
+}
+impl SyscallError {
+    /// Corresponding errno (positive).
+    pub fn errno(self) -> i32 {
+        use SyscallError::*;
+        match self {
+            NotPermitted => 1,
+            NoEntry => 2,
+            NoProcess => 3,
+            Interrupted => 4,
+            Io => 5,
+            TooBig => 7,
+            NoExec => 8,
+            BadFd => 9,
+            NoChild => 10,
+            NoMemory => 12,
+            PermissionDenied => 13,
+            BadAddress => 14,
+            Exists => 17,
+            NotDir => 20,
+            IsDir => 21,
+            InvalidArg => 22,
+            TooManyFiles => 24,
+            NoSpace => 28,
+            BrokenPipe => 32,
+            NoSys => 38,
+            NotEmpty => 39,
+        }
+    }
+}
+impl From<crate::fs::Error> for SyscallError {
+    fn from(err: crate::fs::Error) -> Self {
+        use crate::fs::Error;
+        match err {
+            Error::InvalidArg(_) => SyscallError::InvalidArg,
+            Error::NoSpace => SyscallError::NoSpace,
+            Error::Exists => SyscallError::Exists,
+            Error::BrokenPipe => SyscallError::BrokenPipe,
+            Error::Interrupted => SyscallError::Interrupted,
+        }
+    }
+}
+pub type Result<T> = core::result::Result<T, SyscallError>;
+
+/// End of the user memory of the current process that is accessible contiguously from addr.
+/// If write is true, the memory must be writable too.
+fn accessible_end(addr: usize, write: bool) -> Result<usize> {
+    vma::accessible_end(&my_proc().lock().vmas, addr, write).ok_or(SyscallError::BadAddress)
+}
+
+fn check_range(addr: usize, len: usize, write: bool) -> Result<()> {
+    let limit = accessible_end(addr, write)?;
+    match addr.checked_add(len) {
+        Some(end) if end <= limit => Ok(()),
+        _ => Err(SyscallError::BadAddress),
+    }
+}
+
+/// Check that [addr, addr + len) lies within the user address space of the current process.
+fn check_user_range(addr: usize, len: usize) -> Result<()> {
+    check_range(addr, len, false)
+}
+
+/// Check that [addr, addr + len) is user memory of the current process
+/// that the process may write to.
+fn check_user_writable(addr: usize, len: usize) -> Result<()> {
+    check_range(addr, len, true)
+}
+
+/// Page in [addr, addr + len) of the current process, so that the kernel
+/// does not fault on it. The range must have been checked already.
+fn fault_in(addr: usize, len: usize, write: bool) -> Result<()> {
+    match vma::fault_in(&my_proc(), addr, addr + len, write) {
+        Ok(()) => Ok(()),
+        Err(vma::Fault::NoMemory) => Err(SyscallError::NoMemory),
+        Err(_) => Err(SyscallError::BadAddress),
+    }
+}
+
+/// Fetch the u32 at addr from the current process.
+pub fn fetch_u32(addr: usize) -> Result<u32> {
+    check_user_range(addr, size_of::<u32>())?;
+    fault_in(addr, size_of::<u32>(), false)?;
+    Ok(unsafe { (addr as *const u32).read_unaligned() })
+}
+
+/// Copy the nul-terminated string at addr from the current process,
+/// without the nul. Fails unless the nul is within max bytes.
+pub fn fetch_str(addr: usize, max: usize) -> Result<Vec<u8>> {
+    let end = accessible_end(addr, false)?;
+    let limit = usize::min(end, addr.saturating_add(max));
+    fault_in(addr, limit - addr, false)?;
+    let mem = unsafe { core::slice::from_raw_parts(addr as *const u8, limit - addr) };
+    match mem.iter().position(|c| *c == b'\0') {
+        Some(len) => Ok(mem[..len].to_vec()),
+        None if limit < end => Err(SyscallError::InvalidArg),
+        None => Err(SyscallError::BadAddress),
+    }
+}
+
+/// Copy dst.len() bytes at addr of the current process into dst.
+/// The caller must not hold any spin lock, since the user pages may
+/// have to be paged in, which may sleep.
+pub fn copy_in(addr: usize, dst: &mut [u8]) -> Result<()> {
+    check_user_range(addr, dst.len())?;
+    fault_in(addr, dst.len(), false)?;
+    unsafe { core::ptr::copy_nonoverlapping(addr as *const u8, dst.as_mut_ptr(), dst.len()) };
+    Ok(())
+}
+
+/// Copy src to addr of the current process.
+/// The caller must not hold any spin lock, as with copy_in().
+pub fn copy_out(addr: usize, src: &[u8]) -> Result<()> {
+    check_user_writable(addr, src.len())?;
+    fault_in(addr, src.len(), true)?;
+    unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), addr as *mut u8, src.len()) };
+    Ok(())
+}
+
+/// Fetch the nth 32-bit system call argument.
+pub fn arg_u32(n: usize) -> Result<u32> {
+    let esp = unsafe { (*my_proc().lock().trap_frame).esp };
+    let addr = esp.checked_add(4 + 4 * n).ok_or(SyscallError::BadAddress)?;
+    fetch_u32(addr)
+}
+pub fn arg_i32(n: usize) -> Result<i32> {
+    arg_u32(n).map(|x| x as i32)
+}
+
+/// Fetch the nth word-sized system call argument as a path
+/// and copy it from the current process.
+pub fn arg_str(n: usize) -> Result<String> {
+    let addr = arg_u32(n)? as usize;
+    let s = fetch_str(addr, MAX_PATH)?;
+    String::from_utf8(s).map_err(|_| SyscallError::InvalidArg)
+}
+
+fn syscall(tf: &mut TrapFrame) {
+    let p = my_proc();
+    p.lock().trap_frame = tf;
+
+    let num = tf.eax as usize;
+    let ret = match SYSCALLS.get(num).copied().flatten() {
+        Some(f) => f(),
+        None => {
+            log!("{:?}: unknown sys call {}", *p.lock(), num);
+            Err(SyscallError::NoSys)
+        }
+    };
+    tf.eax = match ret {
+        Ok(val) => val,
+        Err(err) => (-err.errno()) as u32,
+    };
+}
+
+pub fn init() {
+    unsafe { trap::register_handler(trap::T_SYSCALL, syscall) };
+}
@@ -0,0 +1,452 @@
+//! File-system system calls.
+//! Mostly argument checking, since we don't trust
+//! user code, and calls into file.rs and inode.rs.
+
+use super::{arg_i32, arg_str, arg_u32, fetch_str, fetch_u32, Result, SyscallError};
+use super::{check_user_range, check_user_writable, copy_in, copy_out};
+use crate::exec::{self, MAX_ARG};
+use crate::fs::file::{self, File, FileKind, FileRef};
+use crate::fs::inode::{self, FileType, InodeRef, Stat};
+use crate::fs::{log, pipe};
+use crate::memory::PAGE_SIZE;
+use crate::proc::my_proc;
+use crate::vma;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use utils::fs::DirEnt;
+
+// Open modes
+pub const O_RDONLY: i32 = 0x000;
+pub const O_WRONLY: i32 = 0x001;
+pub const O_RDWR: i32 = 0x002;
+pub const O_CREATE: i32 = 0x200;
+
+/// Fetch the nth word-sized system call argument as a file descriptor
+/// and return both the descriptor and the corresponding file.
+fn arg_fd(n: usize) -> Result<(usize, FileRef)> {
+    let fd = arg_i32(n)?;
+    let p = my_proc();
+    let p = p.lock();
+    if fd < 0 {
+        return Err(SyscallError::BadFd);
+    }
+    match p.files.get(fd as usize) {
+        Some(Some(f)) => Ok((fd as usize, f.clone())),
+        _ => Err(SyscallError::BadFd),
+    }
+}
+
+/// Allocate a file descriptor for the given file.
+/// Takes over file reference from caller on success.
+fn fd_alloc(f: FileRef) -> Result<usize> {
+    let p = my_proc();
+    let mut p = p.lock();
+    match p.files.iter().position(|f| f.is_none()) {
+        Some(fd) => {
+            p.files[fd] = Some(f);
+            Ok(fd)
+        }
+        None => Err(SyscallError::TooManyFiles),
+    }
+}
+
+pub fn sys_dup() -> Result<u32> {
+    let (_, f) = arg_fd(0)?;
+    fd_alloc(f).map(|fd| fd as u32)
+}
+
+/// Size of the kernel buffer that read and write data go through.
+/// Pipes and devices copy the data while holding spin locks,
+/// so they must never touch user memory, which may have to be paged in.
+const IO_CHUNK: usize = PAGE_SIZE;
+
+pub fn sys_read() -> Result<u32> {
+    let (_, f) = arg_fd(0)?;
+    let addr = arg_u32(1)? as usize;
+    let n = arg_i32(2)?;
+    if n < 0 {
+        return Err(SyscallError::InvalidArg);
+    }
+    let n = n as usize;
+    check_user_writable(addr, n)?;
+    if !f.readable {
+        return Err(SyscallError::BadFd);
+    }
+    let mut buf = alloc::vec![0u8; usize::min(n, IO_CHUNK)];
+    let mut tot = 0;
+    while tot < n {
+        let m = usize::min(n - tot, buf.len());
+        let got = match f.read(&mut buf[..m]) {
+            Ok(got) => got,
+            Err(err) if tot == 0 => return Err(err.into()),
+            Err(_) => break,
+        };
+        copy_out(addr + tot, &buf[..got])?;
+        tot += got;
+        // A short read means there is nothing more for now.
+        if got < m {
+            break;
+        }
+    }
+    Ok(tot as u32)
+}
+
+pub fn sys_write() -> Result<u32> {
+    let (_, f) = arg_fd(0)?;
+    let addr = arg_u32(1)? as usize;
+    let n = arg_i32(2)?;
+    if n < 0 {
+        return Err(SyscallError::InvalidArg);
+    }
+    let n = n as usize;
+    check_user_range(addr, n)?;
+    if !f.writable {
+        return Err(SyscallError::BadFd);
+    }
+    let mut buf = alloc::vec![0u8; usize::min(n, IO_CHUNK)];
+    let mut tot = 0;
+    while tot < n {
+        let m = usize::min(n - tot, buf.len());
+        copy_in(addr + tot, &mut buf[..m])?;
+        let put = match f.write(&buf[..m]) {
+            Ok(put) => put,
+            Err(err) if tot == 0 => return Err(err.into()),
+            Err(_) => break,
+        };
+        tot += put;
+        if put < m {
+            break;
+        }
+    }
+    Ok(tot as u32)
+}
+
+pub fn sys_close() -> Result<u32> {
+    let (fd, _) = arg_fd(0)?;
+    let f = my_proc().lock().files[fd].take();
+    // The file is closed outside of the process lock since it may sleep.
+    drop(f);
+    Ok(0)
+}
+
+pub fn sys_fstat() -> Result<u32> {
+    let (_, f) = arg_fd(0)?;
+    let addr = arg_u32(1)? as usize;
+    let st = f.stat()?;
+    let src =
+        unsafe { core::slice::from_raw_parts(&st as *const Stat as *const u8, size_of::<Stat>()) };
+    copy_out(addr, src)?;
+    Ok(0)
+}
+
+/// Create the link new for the same inode as old.
+pub fn sys_link() -> Result<u32> {
+    let old = arg_str(0)?;
+    let new = arg_str(1)?;
+
+    let _tx = log::begin_op();
+    let ip = inode::from_name(&old).ok_or(SyscallError::NoEntry)?;
+    {
+        let mut ip = ip.lock();
+        if ip.type_ == FileType::Directory {
+            return Err(SyscallError::NotPermitted);
+        }
+        ip.nlink += 1;
+        ip.update();
+    }
+
+    let linked = match inode::from_name_parent(&new) {
+        Some((dp, name)) => {
+            let mut dp = dp.lock();
+            let ip = ip.lock();
+            if dp.dev() != ip.dev() {
+                Err(SyscallError::NotPermitted)
+            } else {
+                inode::dir_link(&mut dp, name, ip.inum()).map_err(SyscallError::from)
+            }
+        }
+        None => Err(SyscallError::NoEntry),
+    };
+    if linked.is_err() {
+        let mut ip = ip.lock();
+        ip.nlink -= 1;
+        ip.update();
+    }
+    linked.map(|_| 0)
+}
+
+pub fn sys_unlink() -> Result<u32> {
+    let path = arg_str(0)?;
+
+    let _tx = log::begin_op();
+    let (dp, name) = inode::from_name_parent(&path).ok_or(SyscallError::NoEntry)?;
+    let mut dp = dp.lock();
+
+    // Cannot unlink "." or "..".
+    if name == b"." || name == b".." {
+        return Err(SyscallError::InvalidArg);
+    }
+
+    let (ip, off) = inode::dir_lookup(&dp, name).ok_or(SyscallError::NoEntry)?;
+    let mut ip = ip.lock();
+
+    if ip.nlink < 1 {
+        panic!("unlink: nlink < 1");
+    }
+    if ip.type_ == FileType::Directory && !ip.is_dir_empty() {
+        return Err(SyscallError::NotEmpty);
+    }
+
+    let de = DirEnt::default();
+    if dp.write(de.as_bytes(), off)? != size_of::<DirEnt>() {
+        panic!("unlink: write");
+    }
+    if ip.type_ == FileType::Directory {
+        dp.nlink -= 1;
+        dp.update();
+    }
+    drop(dp);
+
+    ip.nlink -= 1;
+    ip.update();
+    Ok(0)
+}
+
+/// Create a new inode at path.
+/// Opening an existing regular file with O_CREATE returns that file.
+fn create(path: &str, type_: FileType, major: u16, minor: u16) -> Result<InodeRef> {
+    let (dp, name) = inode::from_name_parent(&path).ok_or(SyscallError::NoEntry)?;
+    let mut dp = dp.lock();
+
+    if let Some((ip, _)) = inode::dir_lookup(&dp, name) {
+        drop(dp);
+        // Opening an existing file or device with O_CREATE just opens it.
+        let existing = ip.lock().type_;
+        return match (type_, existing) {
+            (FileType::File, FileType::File) | (FileType::File, FileType::Device) => Ok(ip),
+            _ => Err(SyscallError::Exists),
+        };
+    }
+
+    let ip = inode::ialloc(dp.dev(), type_)?;
+    {
+        let mut ip = ip.lock();
+        ip.major = major;
+        ip.minor = minor;
+        ip.nlink = 1;
+        ip.update();
+
+        let mut link = || {
+            if type_ == FileType::Directory {
+                // Create . and .. entries.
+                dp.nlink += 1; // for ".."
+                dp.update();
+                // No ip.nlink += 1 for ".": avoid cyclic ref count.
+                let inum = ip.inum();
+                inode::dir_link(&mut ip, b".", inum)?;
+                inode::dir_link(&mut ip, b"..", dp.inum())?;
+            }
+            inode::dir_link(&mut dp, name, ip.inum())
+        };
+        if let Err(e) = link() {
+            // Undo the allocation; the inode is freed when ip is dropped.
+            if type_ == FileType::Directory {
+                dp.nlink -= 1;
+                dp.update();
+            }
+            ip.nlink = 0;
+            ip.update();
+            return Err(e.into());
+        }
+    }
+    Ok(ip)
+}
+
+pub fn sys_open() -> Result<u32> {
+    let path = arg_str(0)?;
+    let omode = arg_i32(1)?;
+
+    let tx = log::begin_op();
+    let ip = if omode & O_CREATE != 0 {
+        create(&path, FileType::File, 0, 0)?
+    } else {
+        inode::from_name(&path).ok_or(SyscallError::NoEntry)?
+    };
+    let (type_, major) = {
+        let guard = ip.lock();
+        (guard.type_, guard.major)
+    };
+    if type_ == FileType::Directory && omode != O_RDONLY {
+        return Err(SyscallError::IsDir);
+    }
+
+    let readable = omode & O_WRONLY == 0;
+    let writable = omode & O_WRONLY != 0 || omode & O_RDWR != 0;
+    let kind = match type_ {
+        FileType::Device => FileKind::Device { ip, major },
+        _ => FileKind::Inode(ip),
+    };
+    let f = File::new(kind, readable, writable);
+    drop(tx);
+
+    fd_alloc(f).map(|fd| fd as u32)
+}
+
+pub fn sys_mkdir() -> Result<u32> {
+    let path = arg_str(0)?;
+    let _tx = log::begin_op();
+    create(&path, FileType::Directory, 0, 0)?;
+    Ok(0)
+}
+
+pub fn sys_mknod() -> Result<u32> {
+    let path = arg_str(0)?;
+    let major = arg_i32(1)? as u16;
+    let minor = arg_i32(2)? as u16;
+    if major as usize >= file::N_DEV {
+        return Err(SyscallError::InvalidArg);
+    }
+    let _tx = log::begin_op();
+    create(&path, FileType::Device, major, minor)?;
+    Ok(0)
+}
+
+pub fn sys_chdir() -> Result<u32> {
+    let path = arg_str(0)?;
+    let _tx = log::begin_op();
+    let ip = inode::from_name(&path).ok_or(SyscallError::NoEntry)?;
+    if ip.lock().type_ != FileType::Directory {
+        return Err(SyscallError::NotDir);
+    }
+    let old = my_proc().lock().cwd.replace(ip);
+    // Release the old directory outside of the process lock.
+    drop(old);
+    Ok(0)
+}
+
+pub fn sys_exec() -> Result<u32> {
+    let path = arg_str(0)?;
+    let uargv = arg_u32(1)? as usize;
+
+    let mut args = Vec::new();
+    loop {
+        let argc = args.len();
+        if argc >= MAX_ARG {
+            return Err(SyscallError::TooBig);
+        }
+        let addr = uargv
+            .checked_add(4 * argc)
+            .ok_or(SyscallError::BadAddress)?;
+        let uarg = fetch_u32(addr)? as usize;
+        if uarg == 0 {
+            break;
+        }
+        // Arguments must fit in the first stack page anyway.
+        match fetch_str(uarg, PAGE_SIZE) {
+            Ok(arg) => args.push(arg),
+            Err(SyscallError::InvalidArg) => return Err(SyscallError::TooBig),
+            Err(err) => return Err(err),
+        }
+    }
+    let argv: Vec<&[u8]> = args.iter().map(|arg| arg.as_slice()).collect();
+    exec::exec(&path, &argv)
+}
+
+pub fn sys_pipe() -> Result<u32> {
+    let addr = arg_u32(0)? as usize;
+    check_user_writable(addr, 2 * size_of::<i32>())?;
+    let (rf, wf) = pipe::alloc();
+    let fd0 = fd_alloc(rf)?;
+    let fd1 = match fd_alloc(wf) {
+        Ok(fd) => fd,
+        Err(e) => {
+            let rf = my_proc().lock().files[fd0].take();
+            drop(rf);
+            return Err(e);
+        }
+    };
+    let mut fds = [0u8; 2 * size_of::<i32>()];
+    fds[..4].copy_from_slice(&(fd0 as i32).to_ne_bytes());
+    fds[4..].copy_from_slice(&(fd1 as i32).to_ne_bytes());
+    copy_out(addr, &fds)?;
+    Ok(0)
+}
+
+pub fn sys_mmap() -> Result<u32> {
+    let addr = arg_u32(0)? as usize;
+    let len = arg_u32(1)? as usize;
+    let prot = arg_u32(2)?;
+    let flags = arg_u32(3)?;
+    let file = if flags & vma::MAP_ANONYMOUS != 0 {
+        None
+    } else {
+        let (_, f) = arg_fd(4)?;
+        let off = arg_u32(5)? as usize;
+        if !f.is_inode() || !f.readable {
+            return Err(SyscallError::BadFd);
+        }
+        if flags & vma::MAP_SHARED != 0 && prot & vma::PROT_WRITE != 0 && !f.writable {
+            return Err(SyscallError::BadFd);
+        }
+        Some((f, off))
+    };
+    let req = vma::MapRequest {
+        addr,
+        len,
+        prot,
+        flags,
+        file,
+    };
+    let p = my_proc();
+    let start = vma::map(&mut p.lock().vmas, req);
+    start.map(|a| a as u32).ok_or(SyscallError::InvalidArg)
+}
+
+/// End of [addr, addr + len) rounded up to a page boundary.
+fn page_end(addr: usize, len: usize) -> Result<usize> {
+    let end = addr
+        .checked_add(len)
+        .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+        .ok_or(SyscallError::InvalidArg)?;
+    Ok(end / PAGE_SIZE * PAGE_SIZE)
+}
+
+pub fn sys_munmap() -> Result<u32> {
+    let addr = arg_u32(0)? as usize;
+    let len = arg_u32(1)? as usize;
+    if addr % PAGE_SIZE != 0 || len == 0 {
+        return Err(SyscallError::InvalidArg);
+    }
+    let end = page_end(addr, len)?;
+    let removed = {
+        let p = my_proc();
+        let mut p = p.lock();
+        let p = &mut *p;
+        // The other areas are managed by exec, sbrk and the stack.
+        if !vma::only_mapped(&p.vmas, addr, end) {
+            return Err(SyscallError::InvalidArg);
+        }
+        vma::unmap(&mut p.pg_dir, &mut p.vmas, addr, end)
+    };
+    vma::release(removed);
+    Ok(0)
+}
+
+pub fn sys_mprotect() -> Result<u32> {
+    let addr = arg_u32(0)? as usize;
+    let len = arg_u32(1)? as usize;
+    let prot = arg_u32(2)?;
+    if addr % PAGE_SIZE != 0 {
+        return Err(SyscallError::InvalidArg);
+    }
+    let end = page_end(addr, len)?;
+    let p = my_proc();
+    let mut p = p.lock();
+    let p = &mut *p;
+    match vma::protect(&mut p.pg_dir, &mut p.vmas, addr, end, prot) {
+        Ok(()) => Ok(0),
+        Err(vma::ProtectError::InvalidProt) => Err(SyscallError::InvalidArg),
+        // POSIX reports unmapped pages as ENOMEM.
+        Err(vma::ProtectError::Unmapped) => Err(SyscallError::NoMemory),
+    }
+}
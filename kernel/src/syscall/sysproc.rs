@@ -0,0 +1,89 @@
+use super::{arg_i32, arg_u32, check_user_writable, copy_out, Result, SyscallError};
+use crate::proc::{self, my_proc};
+use crate::rtc::{self, TimeSpec, CLOCK_MONOTONIC, CLOCK_REALTIME};
+use crate::trap::{ticks_chan, TICKS};
+
+pub fn sys_fork() -> Result<u32> {
+    proc::fork().ok_or(SyscallError::NoMemory)
+}
+
+pub fn sys_exit() -> Result<u32> {
+    let status = arg_i32(0)?;
+    proc::exit(status);
+}
+
+pub fn sys_wait() -> Result<u32> {
+    // A null pointer means that the caller is not interested in the status.
+    let status = match arg_u32(0)? as usize {
+        0 => None,
+        addr => Some(addr),
+    };
+    if let Some(addr) = status {
+        check_user_writable(addr, core::mem::size_of::<i32>())?;
+    }
+    let (pid, xstatus) = proc::wait().ok_or(SyscallError::NoChild)?;
+    if let Some(addr) = status {
+        copy_out(addr, &xstatus.to_ne_bytes())?;
+    }
+    Ok(pid)
+}
+
+pub fn sys_kill() -> Result<u32> {
+    let pid = arg_u32(0)?;
+    if proc::kill(pid) {
+        Ok(0)
+    } else {
+        Err(SyscallError::NoProcess)
+    }
+}
+
+pub fn sys_getpid() -> Result<u32> {
+    Ok(my_proc().lock().pid)
+}
+
+/// Grow the process memory by n bytes and return the old end.
+pub fn sys_sbrk() -> Result<u32> {
+    let n = arg_i32(0)?;
+    proc::grow(n).ok_or(SyscallError::NoMemory)
+}
+
+pub fn sys_sleep() -> Result<u32> {
+    let n = arg_u32(0)?;
+    let ticks = TICKS.lock();
+    let ticks0 = *ticks;
+    while ticks.wrapping_sub(ticks0) < n {
+        if proc::killed() {
+            return Err(SyscallError::Interrupted);
+        }
+        proc::sleep(ticks_chan(), &ticks);
+    }
+    Ok(0)
+}
+
+/// Return how many clock tick interrupts have occurred since start.
+pub fn sys_uptime() -> Result<u32> {
+    Ok(*TICKS.lock())
+}
+
+/// Return the current time in seconds since the epoch.
+pub fn sys_time() -> Result<u32> {
+    Ok(rtc::time() as u32)
+}
+
+pub fn sys_clock_gettime() -> Result<u32> {
+    let clock_id = arg_u32(0)?;
+    let addr = arg_u32(1)? as usize;
+    let t = match clock_id {
+        CLOCK_REALTIME => rtc::realtime(),
+        CLOCK_MONOTONIC => rtc::monotonic(),
+        _ => return Err(SyscallError::InvalidArg),
+    };
+    let src = unsafe {
+        core::slice::from_raw_parts(
+            &t as *const _ as *const u8,
+            core::mem::size_of::<TimeSpec>(),
+        )
+    };
+    copy_out(addr, src)?;
+    Ok(0)
+}
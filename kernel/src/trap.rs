@@ -1,3 +1,25 @@
+// x86 trap and interrupt constants.
+
+// Processor-defined:
+pub const T_DIVIDE: u32 = 0; // divide error
+pub const T_DEBUG: u32 = 1; // debug exception
+pub const T_NMI: u32 = 2; // non-maskable interrupt
+pub const T_BRKPT: u32 = 3; // breakpoint
+pub const T_OFLOW: u32 = 4; // overflow
+pub const T_BOUND: u32 = 5; // bounds check
+pub const T_ILLOP: u32 = 6; // illegal opcode
+pub const T_DEVICE: u32 = 7; // device not available
+pub const T_DBLFLT: u32 = 8; // double fault
+pub const T_TSS: u32 = 10; // invalid task switch segment
+pub const T_SEGNP: u32 = 11; // segment not present
+pub const T_STACK: u32 = 12; // stack exception
+pub const T_GPFLT: u32 = 13; // general protection fault
+pub const T_PGFLT: u32 = 14; // page fault
+pub const T_FPERR: u32 = 16; // floating point error
+pub const T_ALIGN: u32 = 17; // aligment check
+pub const T_MCHK: u32 = 18; // machine check
+pub const T_SIMDERR: u32 = 19; // SIMD floating point error
+
 // These are arbitrarily chosen, but with care not to overlap
 // processor defined exceptions or interrupt vectors.
 pub const T_SYSCALL: u32 = 64; // system call
@@ -10,8 +32,10 @@ pub const IRQ_IDE: u32 = 14;
 pub const IRQ_ERROR: u32 = 19;
 pub const IRQ_SPURIOUS: u32 = 31;
 
+use super::lock::cli;
 use super::memory::gate;
 use super::memory::seg;
+use super::proc::{self, my_cpu_id};
 use utils::x86;
 
 /// Interrupt descriptor table (shared by all CPUs).
@@ -32,6 +56,11 @@ global_asm!(include_str!(concat!(env!("OUT_DIR"), "/vectors.S")));
       pushl $1
       jmp alltraps
     ...
+    .globl vector8
+    vector8:
+      pushl $8      # error code is pushed by the processor
+      jmp alltraps
+    ...
 
     .data
     .globl VECTORS
@@ -47,7 +76,32 @@ extern "C" {
 }
 
 use super::lock::spin::SpinMutex;
-static TICKS: SpinMutex<u32> = SpinMutex::new("time", 0);
+/// Number of timer interrupts since boot (counted on CPU 0).
+pub static TICKS: SpinMutex<u32> = SpinMutex::new("time", 0);
+
+/// Channel on which processes wait for the next tick.
+pub fn ticks_chan() -> usize {
+    &TICKS as *const _ as usize
+}
+
+/// Function called by trap() for a particular vector.
+pub type Handler = fn(&mut TrapFrame);
+
+/// Registered handlers indexed by trap number (shared by all CPUs).
+static mut HANDLERS: [Option<Handler>; 256] = [None; 256];
+
+/// Register the handler for the trap vector `trap_no`.
+/// Devices pass `T_IRQ0 + irq`; the EOI is sent by trap() after the handler returns.
+/// Must be called before the corresponding interrupt gets enabled.
+pub unsafe fn register_handler(trap_no: u32, handler: Handler) {
+    let slot = &mut HANDLERS[trap_no as usize];
+    assert!(
+        slot.is_none(),
+        "register_handler: {} already registered",
+        trap_no
+    );
+    *slot = Some(handler);
+}
 
 pub fn init() {
     unsafe {
@@ -59,12 +113,16 @@ pub fn init() {
                 0,
             );
         }
+        IDT[T_DBLFLT as usize].set_task((seg::SEG_DFTSS << 3) as u16);
         IDT[T_SYSCALL as usize].set(
             true,
             (seg::SEG_KCODE << 3) as u16,
             VECTORS[T_SYSCALL as usize] as *const u32 as u32,
             seg::dpl::USER,
         );
+        register_handler(T_PGFLT, page_fault);
+        register_handler(T_IRQ0 + IRQ_TIMER, timer_intr);
+        register_handler(T_IRQ0 + IRQ_SPURIOUS, spurious_intr);
     }
 }
 
@@ -105,6 +163,78 @@ pub struct TrapFrame {
     pub ss: u16,
     _padding6: u16,
 }
+impl TrapFrame {
+    /// Whether the trap came from user mode.
+    pub fn from_user(&self) -> bool {
+        (self.cs & 3) as u8 == seg::dpl::USER
+    }
+}
+impl core::fmt::Debug for TrapFrame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(
+            f,
+            "trap {} ({}) err {:#x}",
+            self.trap_no,
+            trap_name(self.trap_no),
+            self.err
+        )?;
+        writeln!(
+            f,
+            "  eip {:#010x} cs {:#06x} eflags {:#010x}",
+            self.eip, self.cs, self.eflags
+        )?;
+        writeln!(
+            f,
+            "  eax {:#010x} ebx {:#010x} ecx {:#010x} edx {:#010x}",
+            self.eax, self.ebx, self.ecx, self.edx
+        )?;
+        writeln!(
+            f,
+            "  esi {:#010x} edi {:#010x} ebp {:#010x}",
+            self.esi, self.edi, self.ebp
+        )?;
+        write!(
+            f,
+            "  ds {:#06x} es {:#06x} fs {:#06x} gs {:#06x}",
+            self.ds, self.es, self.fs, self.gs
+        )?;
+        if self.from_user() {
+            // esp and ss are only pushed when crossing rings.
+            write!(f, "\n  esp {:#010x} ss {:#06x}", self.esp, self.ss)?;
+        }
+        Ok(())
+    }
+}
+
+fn trap_name(trap_no: u32) -> &'static str {
+    match trap_no {
+        T_DIVIDE => "divide error",
+        T_DEBUG => "debug exception",
+        T_NMI => "non-maskable interrupt",
+        T_BRKPT => "breakpoint",
+        T_OFLOW => "overflow",
+        T_BOUND => "bounds check",
+        T_ILLOP => "illegal opcode",
+        T_DEVICE => "device not available",
+        T_DBLFLT => "double fault",
+        T_TSS => "invalid task switch segment",
+        T_SEGNP => "segment not present",
+        T_STACK => "stack exception",
+        T_GPFLT => "general protection fault",
+        T_PGFLT => "page fault",
+        T_FPERR => "floating point error",
+        T_ALIGN => "alignment check",
+        T_MCHK => "machine check",
+        T_SIMDERR => "SIMD floating point error",
+        T_SYSCALL => "system call",
+        n if is_irq(n) => "device interrupt",
+        _ => "unknown",
+    }
+}
+
+fn is_irq(trap_no: u32) -> bool {
+    (T_IRQ0..T_IRQ0 + 32).contains(&trap_no)
+}
 
 pub fn idt_init() {
     const IDT_SZ: usize = core::mem::size_of::<[gate::GateDesc; 256]>();
@@ -112,10 +242,142 @@ pub fn idt_init() {
 }
 
 #[no_mangle]
-pub extern "C" fn trap(trap_frame: *const TrapFrame) {
-    // use super::proc::my_cpu_id;
-    // log!("[cpu:{}] trap", my_cpu_id());
-    super::lapic::eoi();
+pub extern "C" fn trap(trap_frame: *mut TrapFrame) {
+    let tf = unsafe { &mut *trap_frame };
+    let from_user = tf.from_user();
+    let killed = || {
+        proc::try_my_proc()
+            .map(|p| p.lock().killed)
+            .unwrap_or(false)
+    };
+
+    if tf.trap_no == T_SYSCALL && killed() {
+        proc::exit(-1);
+    }
+
+    match unsafe { HANDLERS[tf.trap_no as usize] } {
+        Some(handler) => handler(tf),
+        // Nobody is interested in this interrupt.
+        None if is_irq(tf.trap_no) => {}
+        None => unexpected(tf),
+    }
+    if is_irq(tf.trap_no) {
+        super::lapic::eoi();
+    }
+
+    // Force process exit if it has been killed and is in user space.
+    // (If it is still executing in the kernel, let it keep running
+    // until it gets to the regular system call return.)
+    if from_user && killed() {
+        proc::exit(-1);
+    }
+
+    // Force process to give up CPU on clock tick.
+    if from_user && tf.trap_no == T_IRQ0 + IRQ_TIMER {
+        proc::yield_();
+
+        // Check if the process has been killed since we yielded
+        if killed() {
+            proc::exit(-1);
+        }
+    }
+}
+
+/// Kill the current process if the trap came from user space, otherwise panic.
+fn unexpected(tf: &TrapFrame) {
+    let cpu = cli(my_cpu_id);
+    match proc::try_my_proc() {
+        Some(p) if tf.from_user() => {
+            let mut p = p.lock();
+            println!(
+                super::console::print_color::LIGHT_RED;
+                "{:?}: unexpected trap on cpu {} -- kill proc\n{:?}", *p, cpu, tf
+            );
+            p.killed = true;
+        }
+        _ => panic!("unexpected trap on cpu {}\n{:?}", cpu, tf),
+    }
+}
+
+/// Entered through a task gate on a stack of its own (see vm::seginit).
+/// The state of the faulting code is saved in the task state of the CPU.
+pub extern "C" fn double_fault() -> ! {
+    let cpu = &proc::cpus()[my_cpu_id() as usize];
+    let ts = unsafe { &(*cpu.private.as_ptr()).task_state };
+    panic!(
+        "double fault at eip {:#010x} esp {:#010x}: kernel stack overflow?",
+        ts.eip as usize, ts.esp as usize
+    );
+}
+
+/// Page fault error code: the page was present (protection violation)
+const PF_PRESENT: u32 = 1 << 0;
+/// Page fault error code: caused by a write
+const PF_WRITE: u32 = 1 << 1;
+
+/// Allocate pages of the process that have not been touched yet, and
+/// resolve writes to copy-on-write pages, as far as the memory areas
+/// of the process allow the access; anything else is unexpected.
+/// The kernel pages in user memory before accessing it (see vma::fault_in),
+/// so a fault in kernel mode that cannot be resolved is a bug.
+fn page_fault(tf: &mut TrapFrame) {
+    use super::vma::{self, Fault};
+
+    let va = x86::rcr2() as usize;
+    let write = tf.err & PF_WRITE != 0;
+    let resolved = match proc::try_my_proc() {
+        // Only a write to a copy-on-write page can be resolved.
+        Some(p) if tf.err & PF_PRESENT != 0 && write => vma::copy_on_write(&mut p.lock(), va),
+        Some(_) if tf.err & PF_PRESENT != 0 => Err(Fault::Invalid),
+        // Paging in may need to read a file, so p must be unlocked.
+        Some(p) => vma::page_in(&p, va, write),
+        None => Err(Fault::Invalid),
+    };
+    match resolved {
+        Ok(()) => {}
+        Err(Fault::StackOverflow) if tf.from_user() => {
+            let p = proc::my_proc();
+            let mut p = p.lock();
+            println!(
+                super::console::print_color::LIGHT_RED;
+                "{:?}: stack overflow at {:#010x} -- kill proc", *p, va
+            );
+            p.killed = true;
+        }
+        Err(Fault::NoMemory) if tf.from_user() => {
+            let p = proc::my_proc();
+            let mut p = p.lock();
+            println!(
+                super::console::print_color::LIGHT_RED;
+                "{:?}: out of memory at {:#010x} -- kill proc", *p, va
+            );
+            p.killed = true;
+        }
+        Err(_) => {
+            println!(
+                super::console::print_color::LIGHT_RED;
+                "page fault at {:#010x}", va
+            );
+            unexpected(tf);
+        }
+    }
+}
+
+fn timer_intr(_: &mut TrapFrame) {
+    if cli(my_cpu_id) == 0 {
+        let mut ticks = TICKS.lock();
+        *ticks = ticks.wrapping_add(1);
+        proc::wakeup(ticks_chan());
+    }
+}
+
+fn spurious_intr(tf: &mut TrapFrame) {
+    log!(
+        "cpu{}: spurious interrupt at {:#x}:{:#x}",
+        cli(my_cpu_id),
+        tf.cs,
+        tf.eip
+    );
 }
 
 extern "C" {
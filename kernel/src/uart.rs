@@ -1,32 +1,82 @@
+//! 8250/16550 serial port (COM1)
+
+use super::console;
+use super::lock::spin::SpinMutex;
+use super::trap::{self, TrapFrame};
 use utils::x86;
 
 const COM1: u16 = 0x03F8;
 
+/// receive holding register (for input bytes)
+const RHR: u16 = 0;
+/// transmit holding register (for output bytes)
+const THR: u16 = 0;
+/// interrupt enable register
+const IER: u16 = 1;
+const IER_RX_ENABLE: u8 = 1 << 0;
+const IER_TX_ENABLE: u8 = 1 << 1;
+/// interrupt identification register
+const IIR: u16 = 2;
+/// line status register
+const LSR: u16 = 5;
+/// input is waiting to be read from RHR
+const LSR_RX_READY: u8 = 1 << 0;
+/// THR can accept another character to send
+const LSR_TX_IDLE: u8 = 1 << 5;
+
 static mut IS_UART: bool = false;
 
+const TX_BUF_SIZE: usize = 512;
+
+/// Output bytes waiting for the transmitter
+struct TxBuf {
+    buf: [u8; TX_BUF_SIZE],
+    /// read index: next byte to send
+    r: usize,
+    /// write index
+    w: usize,
+}
+
+static TX: SpinMutex<TxBuf> = SpinMutex::new(
+    "uart",
+    TxBuf {
+        buf: [0; TX_BUF_SIZE],
+        r: 0,
+        w: 0,
+    },
+);
+
+fn read_reg(reg: u16) -> u8 {
+    x86::inb(COM1 + reg)
+}
+fn write_reg(reg: u16, data: u8) {
+    x86::outb(COM1 + reg, data)
+}
+
 pub fn init() {
     // Turn off the FIFO
-    x86::outb(COM1 + 2, 0);
+    write_reg(2, 0);
 
     // 9600 baud, 8 data bits, 1 stop bit, parity off.
-    x86::outb(COM1 + 3, 0x80);
-    x86::outb(COM1 + 0, (115200 / 9600) as u8); // divisor value (lower)
-    x86::outb(COM1 + 1, 0); // divisor value (higher)
-    x86::outb(COM1 + 3, 0x03); // Lock divisor, 8 data bits.
-    x86::outb(COM1 + 4, 0);
-    x86::outb(COM1 + 1, 0x01); // Enable receive interrupts.
+    write_reg(3, 0x80);
+    write_reg(0, (115200 / 9600) as u8); // divisor value (lower)
+    write_reg(1, 0); // divisor value (higher)
+    write_reg(3, 0x03); // Lock divisor, 8 data bits.
+    write_reg(4, 0);
+    write_reg(IER, IER_RX_ENABLE | IER_TX_ENABLE); // Enable receive and transmit interrupts.
 
     // If status is 0xFF no serial port.
-    if x86::inb(COM1 + 5) == 0xFF {
+    if read_reg(LSR) == 0xFF {
         return;
     }
     unsafe { IS_UART = true };
 
     // Acknowledge pre-existing interrupt conditions;
     // enable interrupts.
-    x86::inb(COM1 + 2);
-    x86::inb(COM1 + 0);
-    super::ioapic::enable(super::trap::IRQ_COM1, 0);
+    read_reg(IIR);
+    read_reg(RHR);
+    unsafe { trap::register_handler(trap::T_IRQ0 + trap::IRQ_COM1, uart_intr) };
+    super::ioapic::enable(trap::IRQ_COM1, 0);
 }
 
 pub fn puts(s: &str) {
@@ -38,12 +88,69 @@ pub fn puts(s: &str) {
     }
 }
 
-fn putc(c: u8) {
+/// Add a byte to the output buffer and start sending if the
+/// transmitter is idle. Only spins when the buffer is full.
+pub fn putc(c: u8) {
+    if !unsafe { IS_UART } {
+        return;
+    }
+    let mut tx = TX.lock();
+    if tx.w == tx.r + TX_BUF_SIZE {
+        // buffer is full; make room by sending the oldest byte.
+        send_sync(&mut tx);
+    }
+    let w = tx.w;
+    tx.buf[w % TX_BUF_SIZE] = c;
+    tx.w += 1;
+    start(&mut tx);
+}
+
+/// If the transmitter is idle and a byte is waiting, send it.
+/// The transmitter raises an interrupt once it is done.
+fn start(tx: &mut TxBuf) {
+    if tx.r != tx.w && read_reg(LSR) & LSR_TX_IDLE != 0 {
+        write_reg(THR, tx.buf[tx.r % TX_BUF_SIZE]);
+        tx.r += 1;
+    }
+}
+
+/// Wait for the transmitter and send one buffered byte.
+fn send_sync(tx: &mut TxBuf) {
+    while read_reg(LSR) & LSR_TX_IDLE == 0 {}
+    write_reg(THR, tx.buf[tx.r % TX_BUF_SIZE]);
+    tx.r += 1;
+}
+
+/// Send all buffered output without waiting for interrupts.
+/// Used when interrupts are off for good, e.g. on panic.
+pub fn flush() {
     if !unsafe { IS_UART } {
         return;
     }
-    while x86::inb(COM1 + 5) & 0x20 == 0 {
-        super::lapic::micro_delay(10);
+    let mut tx = TX.lock();
+    while tx.r != tx.w {
+        send_sync(&mut tx);
+    }
+}
+
+/// Read one input byte if any is waiting.
+fn getc() -> Option<u8> {
+    if read_reg(LSR) & LSR_RX_READY != 0 {
+        Some(read_reg(RHR))
+    } else {
+        None
     }
-    x86::outb(COM1 + 0, c);
+}
+
+/// Handle a uart interrupt, raised because input has arrived,
+/// or the uart is ready for more output, or both.
+fn uart_intr(_: &mut TrapFrame) {
+    // reading IIR acknowledges the interrupt.
+    read_reg(IIR);
+
+    while let Some(c) = getc() {
+        console::intr(c);
+    }
+
+    start(&mut TX.lock());
 }
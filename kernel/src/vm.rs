@@ -1,9 +1,12 @@
+use super::lock::spin::SpinMutex;
 use super::memory::pg_dir::{
     self, ent_flag, PageDirEntry, PageDirectory, PageTable, PageTableEntry,
 };
 use super::memory::{p2v, v2p, Page};
 use super::memory::{DEVSPACE, EXTMEM, KERNBASE, KERNLINK, PAGE_SIZE, PHYSTOP};
+use super::memory::{KSTACKBASE, KSTACKSIZE};
 use alloc::boxed::Box;
+use core::ptr::NonNull;
 use lazy_static::lazy_static;
 use utils::prelude::*;
 use utils::x86;
@@ -19,6 +22,7 @@ struct Kmap {
 /// Run once on entry on each CPU.
 pub fn seginit() {
     use super::memory::seg::*;
+    use super::proc::TaskState;
 
     // Map "logical" addresses to virtual addresses using identity map.
     // Cannot share a CODE descriptor for both kernel and user
@@ -31,10 +35,31 @@ pub fn seginit() {
     c.gdt[SEG_KDATA] = SegDesc::seg(type_data, 0, 0xFFFFFFFF, 0);
     c.gdt[SEG_UCODE] = SegDesc::seg(type_code, 0, 0xFFFFFFFF, dpl::USER);
     c.gdt[SEG_UDATA] = SegDesc::seg(type_data, 0, 0xFFFFFFFF, dpl::USER);
+
+    // A double fault, usually a kernel stack overflowing into its guard page,
+    // leaves no stack to handle it on, so it switches to a task of its own.
+    let c = &mut *c;
+    let tss_size = (core::mem::size_of::<TaskState>() - 1) as u32;
+    let ts = &mut c.df_task_state;
+    ts.cr3 = v2p(VAddr::from(*KPG_DIR)).raw() as *mut u8;
+    ts.eip = super::trap::double_fault as usize as *mut u32;
+    ts.esp = unsafe { c.df_stack.as_mut_ptr().add(KSTACKSIZE) } as *mut u32;
+    ts.cs = (SEG_KCODE << 3) as u16;
+    ts.ss = (SEG_KDATA << 3) as u16;
+    ts.ds = (SEG_KDATA << 3) as u16;
+    ts.es = (SEG_KDATA << 3) as u16;
+    ts.iomb = 0xFFFF;
+    c.gdt[SEG_DFTSS] = SegDesc::tss(seg_type::STS_T32A, ts as *const _ as u32, tss_size, 0);
+    // The task switch saves the state of the faulting code in the current TSS,
+    // so there must be one even before the first process runs.
+    let ts = &c.task_state;
+    c.gdt[SEG_TSS] = SegDesc::tss(seg_type::STS_T32A, ts as *const _ as u32, tss_size, 0);
+
     x86::lgdt(
         c.gdt.as_ptr() as *const u8,
         core::mem::size_of::<[SegDesc; NSEGS]>() as u16,
     );
+    x86::ltr((SEG_TSS as u16) << 3);
 }
 
 // Return the reference of the PTE in page table pg_dir
@@ -138,7 +163,7 @@ pub fn setup_kvm() -> Option<Box<PageDirectory>> {
     ];
 
     let mut pg_dir = PageDirectory::zero_boxed();
-    if p2v(PHYSTOP) > DEVSPACE {
+    if p2v(PHYSTOP) > KSTACKBASE {
         panic!("PHYSTOP too high");
     }
     {
@@ -158,6 +183,12 @@ pub fn setup_kvm() -> Option<Box<PageDirectory>> {
             }
         }
     }
+    // kernel stacks
+    let kstack_pg_tab = v2p(VAddr::from(&**KSTACK_PG_TAB.lock()));
+    pg_dir[pg_dir::pdx(KSTACKBASE)] = PageDirEntry::new_table(
+        kstack_pg_tab.raw() as u32,
+        ent_flag::PRESENT | ent_flag::WRITABLE,
+    );
     Some(pg_dir)
 }
 
@@ -179,20 +210,76 @@ pub fn switch_kvm() {
 }
 
 /// Free a page table and all the physical memory pages in the user part.
-fn free_vm(mut pg_dir: Box<PageDirectory>) {
+pub fn free_vm(mut pg_dir: Box<PageDirectory>) {
     uvm::dealloc(&mut pg_dir, KERNBASE.raw(), 0);
+    // The page table of the kernel stacks is shared.
+    pg_dir[pg_dir::pdx(KSTACKBASE)] = PageDirEntry::zero();
     for ent in pg_dir
         .iter()
         .filter(|ent| ent.flags_check(ent_flag::PRESENT))
     {
-        let v = p2v(ent.addr()).cast::<Page>();
+        let v = p2v(ent.addr());
         let t = unsafe { Box::from_raw(v.mut_ptr()) };
         drop(t);
     }
 }
 
+/// Pages of a kernel stack
+const KSTACK_PAGES: usize = KSTACKSIZE / PAGE_SIZE;
+/// Number of kernel stacks, each above a guard page, that fit in one page table
+const NKSTACK: usize = pg_dir::NPTENTRIES / (KSTACK_PAGES + 1);
+
+lazy_static! {
+    /// Page table of the kernel stacks, shared by every page directory.
+    /// Its entry i * (KSTACK_PAGES + 1) is the guard page of the i-th stack
+    /// and is never mapped, so that overflowing the stack faults
+    /// instead of corrupting the memory beneath it.
+    static ref KSTACK_PG_TAB: SpinMutex<Box<PageTable>> =
+        SpinMutex::new("kstack", PageTable::zero_boxed());
+}
+
+/// Unmap and free n pages of the kernel stack page table from the idx-th one.
+fn kstack_unmap(pg_tab: &mut PageTable, idx: usize, n: usize) {
+    for i in idx..idx + n {
+        let page = NonNull::new(p2v(pg_tab[i].addr()).mut_ptr()).unwrap();
+        super::kalloc::kfree(page);
+        pg_tab[i] = PageTableEntry::zero();
+        x86::invlpg(KSTACKBASE.raw() + i * PAGE_SIZE);
+    }
+}
+
+/// Allocate a kernel stack of KSTACKSIZE bytes above an unmapped guard page.
+/// Returns the bottom of the stack.
+pub fn kstack_alloc() -> Option<*mut u8> {
+    let mut pg_tab = KSTACK_PG_TAB.lock();
+    let slot = (0..NKSTACK).find(|i| {
+        let idx = i * (KSTACK_PAGES + 1) + 1;
+        !pg_tab[idx].flags_check(ent_flag::PRESENT)
+    })?;
+    let idx = slot * (KSTACK_PAGES + 1) + 1;
+    for i in 0..KSTACK_PAGES {
+        let mem = match super::kalloc::kalloc() {
+            Some(mem) => mem,
+            None => {
+                kstack_unmap(&mut pg_tab, idx, i);
+                return None;
+            }
+        };
+        let pa = v2p(VAddr::from(mem.as_ptr() as *const Page));
+        pg_tab[idx + i] = PageTableEntry::new(pa, ent_flag::PRESENT | ent_flag::WRITABLE);
+    }
+    Some((KSTACKBASE.raw() + idx * PAGE_SIZE) as *mut u8)
+}
+
+/// Free a kernel stack returned by kstack_alloc().
+pub fn kstack_free(stack: *mut u8) {
+    let idx = (stack as usize - KSTACKBASE.raw()) / PAGE_SIZE;
+    kstack_unmap(&mut KSTACK_PG_TAB.lock(), idx, KSTACK_PAGES);
+}
+
 pub mod uvm {
     use super::*;
+    use crate::fs::inode::InodeGuard;
     use crate::lock::cli;
     use crate::memory::{seg, v2p, KSTACKSIZE};
     use crate::proc::{my_cpu, ProcessRef, TaskState};
@@ -215,6 +302,220 @@ pub mod uvm {
         unsafe { core::ptr::copy_nonoverlapping(init_code.as_ptr(), mem, init_code.len()) };
     }
 
+    /// Map the present pages of [start, end) in pg_dir to the same frames in new.
+    /// Unless share is set, the pages become read-only copy-on-write in both,
+    /// so that neither sees the other's writes even if it is made writable later.
+    pub fn copy_range(
+        pg_dir: &mut PageDirectory,
+        new: &mut PageDirectory,
+        start: usize,
+        end: usize,
+        share: bool,
+    ) -> Option<()> {
+        for va in (start..end).step_by(PAGE_SIZE) {
+            let va = VAddr::from_raw(va);
+            let pte = match walk_page_dir(pg_dir, va, false) {
+                Some(pte) if pte.flags_check(ent_flag::PRESENT) => pte,
+                // not touched yet
+                _ => continue,
+            };
+            let pa = pte.addr();
+            if !share && !pte.flags_check(ent_flag::COW) {
+                let flags = (pte.flags() & !ent_flag::WRITABLE) | ent_flag::COW;
+                *pte = PageTableEntry::new(pa, flags);
+                x86::invlpg(va.raw());
+            }
+            let flags = pte.flags();
+            map_pages(new, va.cast(), PAGE_SIZE, pa, flags)?;
+            let page = core::ptr::NonNull::new(p2v(pa).mut_ptr()).unwrap();
+            crate::kalloc::incref(page);
+        }
+        Some(())
+    }
+
+    /// Map page, which must come from kalloc(), at va with `perm | USER`.
+    /// The mapping takes over the caller's reference to page.
+    pub fn map_page(
+        pg_dir: &mut PageDirectory,
+        va: usize,
+        page: core::ptr::NonNull<Page>,
+        perm: u32,
+    ) -> Option<()> {
+        let pa = v2p(VAddr::from(page.as_ptr() as *const Page));
+        map_pages(
+            pg_dir,
+            VAddr::from_raw(va),
+            PAGE_SIZE,
+            pa,
+            perm | ent_flag::USER,
+        )
+    }
+
+    /// Whether the page at va is present.
+    pub fn is_present(pg_dir: &mut PageDirectory, va: usize) -> bool {
+        walk_page_dir(pg_dir, VAddr::from_raw(va), false)
+            .map_or(false, |pte| pte.flags_check(ent_flag::PRESENT))
+    }
+
+    /// Whether the page at va is a present copy-on-write user page.
+    pub fn is_cow(pg_dir: &mut PageDirectory, va: usize) -> bool {
+        walk_page_dir(pg_dir, VAddr::from_raw(va), false).map_or(false, |pte| {
+            pte.flags_check(ent_flag::PRESENT | ent_flag::USER | ent_flag::COW)
+        })
+    }
+
+    /// Whether the page at va is present and has been written to.
+    pub fn is_dirty(pg_dir: &mut PageDirectory, va: usize) -> bool {
+        walk_page_dir(pg_dir, VAddr::from_raw(va), false).map_or(false, |pte| {
+            pte.flags_check(ent_flag::PRESENT | ent_flag::DIRTY)
+        })
+    }
+
+    /// Give pg_dir its own writable copy of the copy-on-write page containing va.
+    /// If nobody else shares the page any more, it is just made writable.
+    /// Returns None if va is not in a copy-on-write page or memory is exhausted.
+    pub fn copy_on_write(pg_dir: &mut PageDirectory, va: usize) -> Option<()> {
+        if va >= KERNBASE.raw() {
+            return None;
+        }
+        let va = VAddr::<Page>::from_raw(va).round_down(PAGE_SIZE);
+        let pte = walk_page_dir(pg_dir, va, false)?;
+        if !pte.flags_check(ent_flag::PRESENT | ent_flag::USER | ent_flag::COW) {
+            return None;
+        }
+        let pa = pte.addr();
+        let flags = (pte.flags() & !ent_flag::COW) | ent_flag::WRITABLE;
+        let page = core::ptr::NonNull::new(p2v(pa).mut_ptr()).unwrap();
+        if crate::kalloc::ref_count(page) == 1 {
+            *pte = PageTableEntry::new(pa, flags);
+        } else {
+            let mem = crate::kalloc::kalloc()?;
+            unsafe { core::ptr::copy_nonoverlapping(page.as_ptr(), mem.as_ptr(), 1) };
+            let mem_pa = v2p(VAddr::from(mem.as_ptr() as *const Page));
+            *pte = PageTableEntry::new(mem_pa, flags);
+            crate::kalloc::kfree(page);
+        }
+        x86::invlpg(va.raw());
+        Some(())
+    }
+
+    /// Replace the USER and WRITABLE bits of the present pages in [start, end)
+    /// with those of perm. Copy-on-write pages stay read-only until they are copied.
+    pub fn protect(pg_dir: &mut PageDirectory, start: usize, end: usize, perm: u32) {
+        for va in (start..end).step_by(PAGE_SIZE) {
+            let pte = match walk_page_dir(pg_dir, VAddr::from_raw(va), false) {
+                Some(pte) if pte.flags_check(ent_flag::PRESENT) => pte,
+                _ => continue,
+            };
+            let mut perm = perm & (ent_flag::USER | ent_flag::WRITABLE);
+            if pte.flags_check(ent_flag::COW) {
+                perm &= !ent_flag::WRITABLE;
+            }
+            let flags = (pte.flags() & !(ent_flag::USER | ent_flag::WRITABLE)) | perm;
+            *pte = PageTableEntry::new(pte.addr(), flags);
+            x86::invlpg(va);
+        }
+    }
+
+    /// Allocate page tables and physical memory to grow process from old_sz to
+    /// new_sz, which need not be page aligned. The new pages get `perm | USER`.
+    /// Returns new size or None on error.
+    pub fn alloc(
+        pg_dir: &mut PageDirectory,
+        old_sz: usize,
+        new_sz: usize,
+        perm: u32,
+    ) -> Option<usize> {
+        if new_sz >= KERNBASE.raw() {
+            return None;
+        }
+        if new_sz < old_sz {
+            return Some(old_sz);
+        }
+
+        let mut a = VAddr::<Page>::from_raw(old_sz).round_up(PAGE_SIZE);
+        while a.raw() < new_sz {
+            let mem = match crate::kalloc::kalloc() {
+                Some(mem) => mem,
+                None => {
+                    dealloc(pg_dir, new_sz, old_sz);
+                    return None;
+                }
+            };
+            unsafe { rlibc::memset(mem.as_ptr() as *mut u8, 0, PAGE_SIZE) };
+            let mem_pa = v2p(VAddr::from(mem.as_ptr() as *const Page));
+            if map_pages(pg_dir, a.cast(), PAGE_SIZE, mem_pa, perm | ent_flag::USER).is_none() {
+                crate::kalloc::kfree(mem);
+                dealloc(pg_dir, new_sz, old_sz);
+                return None;
+            }
+            a += 1;
+        }
+        Some(new_sz)
+    }
+
+    /// Load a program segment into pg_dir at va, which need not be page-aligned.
+    /// The pages from va to va+sz must already be mapped.
+    pub fn load(
+        pg_dir: &mut PageDirectory,
+        va: usize,
+        ip: &InodeGuard,
+        offset: usize,
+        sz: usize,
+    ) -> Option<()> {
+        let mut i = 0;
+        while i < sz {
+            let a = va + i;
+            let pte = walk_page_dir(pg_dir, VAddr::from_raw(a), false)
+                .expect("uvm::load: address should exist");
+            let n = usize::min(sz - i, PAGE_SIZE - a % PAGE_SIZE);
+            let dst = unsafe {
+                let page = p2v(pte.addr()).mut_ptr() as *mut u8;
+                core::slice::from_raw_parts_mut(page.add(a % PAGE_SIZE), n)
+            };
+            match ip.read(dst, offset + i) {
+                Ok(read) if read == n => {}
+                _ => return None,
+            }
+            i += n;
+        }
+        Some(())
+    }
+
+    /// Map user virtual address to kernel address.
+    fn to_kernel(pg_dir: &mut PageDirectory, va: VAddr<Page>) -> Option<VAddr<Page>> {
+        let pte = walk_page_dir(pg_dir, va, false)?;
+        if !pte.flags_check(ent_flag::PRESENT | ent_flag::USER) {
+            return None;
+        }
+        Some(p2v(pte.addr()))
+    }
+
+    /// Copy src to user address va in page table pg_dir.
+    /// Most useful when pg_dir is not the current page table.
+    pub fn copy_out(pg_dir: &mut PageDirectory, mut va: usize, mut src: &[u8]) -> Option<()> {
+        while !src.is_empty() {
+            let va0 = VAddr::<Page>::from_raw(va).round_down(PAGE_SIZE);
+            // The write goes through the kernel mapping,
+            // so break copy-on-write sharing by hand.
+            let cow = walk_page_dir(pg_dir, va0, false)
+                .map_or(false, |pte| pte.flags_check(ent_flag::COW));
+            if cow {
+                copy_on_write(pg_dir, va0.raw())?;
+            }
+            let page = to_kernel(pg_dir, va0)?;
+            let off = va - va0.raw();
+            let n = usize::min(PAGE_SIZE - off, src.len());
+            unsafe {
+                let dst = (page.mut_ptr() as *mut u8).add(off);
+                core::ptr::copy_nonoverlapping(src.as_ptr(), dst, n);
+            }
+            src = &src[n..];
+            va += n;
+        }
+        Some(())
+    }
+
     /// Switch TSS and h/w page table to correspond to process p.
     pub fn switch(p: &ProcessRef) {
         let p = p.lock();
@@ -242,7 +543,36 @@ pub mod uvm {
     /// new_sz.  old_sz and new_sz need not be page-aligned, nor does new_sz
     /// need to be less than old_sz.  old_sz can be larger than the actual
     /// process size.  Returns the new process size.
-    pub fn dealloc(_pg_dir: &mut PageDirectory, _old_sz: usize, _new_sz: usize) {
-        todo!()
+    pub fn dealloc(pg_dir: &mut PageDirectory, old_sz: usize, new_sz: usize) -> usize {
+        use pg_dir::{pdx, NPTENTRIES};
+        if new_sz >= old_sz {
+            return old_sz;
+        }
+
+        let mut a = VAddr::<Page>::from_raw(new_sz).round_up(PAGE_SIZE).raw();
+        while a < old_sz {
+            let va = VAddr::from_raw(a);
+            match walk_page_dir(pg_dir, va, false) {
+                None => {
+                    // Skip the whole range covered by the missing page table.
+                    match (pdx(va) + 1).checked_mul(NPTENTRIES * PAGE_SIZE) {
+                        Some(next) => a = next,
+                        None => break,
+                    }
+                    continue;
+                }
+                Some(pte) if pte.flags_check(ent_flag::PRESENT) => {
+                    let pa = pte.addr();
+                    assert!(!pa.is_null(), "uvm::dealloc: kfree");
+                    let page = core::ptr::NonNull::new(p2v(pa).mut_ptr()).unwrap();
+                    crate::kalloc::kfree(page);
+                    *pte = PageTableEntry::zero();
+                    x86::invlpg(a);
+                }
+                Some(_) => {}
+            }
+            a += PAGE_SIZE;
+        }
+        new_sz
     }
 }
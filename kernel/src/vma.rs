@@ -0,0 +1,755 @@
+//! Virtual memory areas of a process
+//!
+//! Every valid user address of a process lies in one of its areas,
+//! which records what the address is used for and how it may be accessed.
+//! exec sets up the text, data and heap areas from address 0 up,
+//! and the stack area beneath STACK_TOP with an unmapped guard page below it;
+//! mmap adds areas in [MMAP_BASE, MMAP_TOP).
+//! A page not present yet is allocated by the page fault handler on first touch:
+//! private pages are zero-filled or read from the file, and are copied
+//! on write after fork; shared pages are kept in a SharedPages object,
+//! which every process mapping the area refers to. All shared mappings
+//! of the same inode use one SharedPages object, found in SHARED_FILES.
+//! Dirty pages of shared file mappings are written back on unmap.
+
+use crate::fs::file::FileRef;
+use crate::kalloc;
+use crate::lock::spin::SpinMutex;
+use crate::memory::pg_dir::{ent_flag, PageDirectory};
+use crate::memory::{Page, KERNBASE, PAGE_SIZE};
+use crate::proc::{Process, ProcessRef};
+use crate::vm::{self, uvm};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use lazy_static::lazy_static;
+
+pub const PROT_NONE: u32 = 0x0;
+pub const PROT_READ: u32 = 0x1;
+pub const PROT_WRITE: u32 = 0x2;
+pub const PROT_EXEC: u32 = 0x4;
+/// All valid protection bits
+pub const PROT_MASK: u32 = PROT_READ | PROT_WRITE | PROT_EXEC;
+
+/// Changes are visible to other processes and written back to the file
+pub const MAP_SHARED: u32 = 0x01;
+/// Changes are private to the process
+pub const MAP_PRIVATE: u32 = 0x02;
+/// Map exactly at the given address
+pub const MAP_FIXED: u32 = 0x10;
+/// Not backed by a file; fd and offset are ignored
+pub const MAP_ANONYMOUS: u32 = 0x20;
+
+/// Lowest address of mapped areas
+pub const MMAP_BASE: usize = 0x4000_0000;
+/// Highest address (exclusive) of mapped areas
+pub const MMAP_TOP: usize = KERNBASE.raw();
+
+/// Top (exclusive) of the user stack
+pub const STACK_TOP: usize = MMAP_BASE;
+/// Maximum size of the user stack (RLIMIT_STACK)
+pub const STACK_LIMIT: usize = 256 * PAGE_SIZE;
+/// Highest address (exclusive) the heap can grow to,
+/// leaving room for the largest stack and its guard page
+pub const HEAP_TOP: usize = STACK_TOP - STACK_LIMIT - PAGE_SIZE;
+
+/// What an area is used for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmaKind {
+    /// program code, read-only
+    Text,
+    /// initialized and uninitialized program data
+    Data,
+    /// grown and shrunk by sbrk
+    Heap,
+    /// user stack
+    Stack,
+    /// unmapped page beneath the stack
+    Guard,
+    /// added by mmap
+    Mapped,
+}
+
+/// Where the pages of an area come from
+#[derive(Clone)]
+enum Backing {
+    /// private zero-filled pages
+    Anonymous,
+    /// private copy of a file
+    File(FileRef),
+    /// pages shared with the other processes mapping the same object
+    Shared(Arc<SharedPages>),
+}
+
+/// An area [start, end) of a process
+#[derive(Clone)]
+pub struct Vma {
+    pub kind: VmaKind,
+    pub start: usize,
+    pub end: usize,
+    pub prot: u32,
+    /// Offset of start in the file or the shared object
+    off: usize,
+    backing: Backing,
+}
+
+impl Vma {
+    /// An area of private zero-filled pages
+    pub fn anonymous(kind: VmaKind, start: usize, end: usize, prot: u32) -> Self {
+        Self {
+            kind,
+            start,
+            end,
+            prot,
+            off: 0,
+            backing: Backing::Anonymous,
+        }
+    }
+    fn contains(&self, va: usize) -> bool {
+        self.start <= va && va < self.end
+    }
+    fn is_shared(&self) -> bool {
+        matches!(self.backing, Backing::Shared(_))
+    }
+    /// Page table permissions for the pages of this area
+    fn perm(&self) -> u32 {
+        let mut perm = 0;
+        if self.prot & (PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+            perm |= ent_flag::USER;
+        }
+        if self.prot & PROT_WRITE != 0 {
+            perm |= ent_flag::WRITABLE;
+        }
+        perm
+    }
+    /// The part of this area in [start, end)
+    fn slice(&self, start: usize, end: usize) -> Self {
+        let start = usize::max(start, self.start);
+        let end = usize::min(end, self.end);
+        Self {
+            start,
+            end,
+            off: self.off + (start - self.start),
+            ..self.clone()
+        }
+    }
+}
+
+struct Slot {
+    frame: Option<NonNull<Page>>,
+    /// Modified since it was last written back
+    dirty: bool,
+}
+
+/// Pages of a shared mapping, indexed by their page number in the file
+/// or, if anonymous, in the first mapping.
+/// Holds one reference to each resident frame.
+struct SharedPages {
+    slots: SpinMutex<BTreeMap<usize, Slot>>,
+    /// The file the pages are read from and written back to
+    file: Option<FileRef>,
+}
+unsafe impl Send for SharedPages {}
+unsafe impl Sync for SharedPages {}
+
+lazy_static! {
+    /// The pages of the shared mappings of each inode, keyed by the address
+    /// of the inode, which stays the same while the file keeps it alive.
+    static ref SHARED_FILES: SpinMutex<BTreeMap<usize, Weak<SharedPages>>> =
+        SpinMutex::new("shared files", BTreeMap::new());
+}
+
+impl SharedPages {
+    fn new(file: Option<FileRef>) -> Self {
+        Self {
+            slots: SpinMutex::new("shared pages", BTreeMap::new()),
+            file,
+        }
+    }
+
+    /// The pages shared by every shared mapping of the inode of file.
+    fn of_file(file: FileRef) -> Arc<Self> {
+        let key = Arc::as_ptr(file.inode().expect("SharedPages::of_file")) as usize;
+        let mut files = SHARED_FILES.lock();
+        if let Some(pages) = files.get(&key).and_then(Weak::upgrade) {
+            return pages;
+        }
+        let dead: Vec<usize> = files
+            .iter()
+            .filter(|(_, pages)| pages.strong_count() == 0)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in dead {
+            files.remove(&key);
+        }
+        let pages = Arc::new(Self::new(Some(file)));
+        files.insert(key, Arc::downgrade(&pages));
+        pages
+    }
+
+    /// Get the idx-th page, reading it in if necessary.
+    /// The caller gets a reference of its own.
+    fn get(&self, idx: usize) -> Option<NonNull<Page>> {
+        if let Some(frame) = self.slots.lock().get(&idx).and_then(|s| s.frame) {
+            kalloc::incref(frame);
+            return Some(frame);
+        }
+        let file = self.file.as_ref().map(|f| (f, idx * PAGE_SIZE));
+        let mem = new_page(file)?;
+        let mut slots = self.slots.lock();
+        let slot = slots.entry(idx).or_insert(Slot {
+            frame: None,
+            dirty: false,
+        });
+        let frame = match slot.frame {
+            // Somebody else has read it in meanwhile.
+            Some(frame) => {
+                kalloc::kfree(mem);
+                frame
+            }
+            None => {
+                slot.frame = Some(mem);
+                mem
+            }
+        };
+        kalloc::incref(frame);
+        Some(frame)
+    }
+
+    fn mark_dirty(&self, idx: usize) {
+        if let Some(slot) = self.slots.lock().get_mut(&idx) {
+            slot.dirty = true;
+        }
+    }
+
+    /// Write dirty pages back to the file. May sleep.
+    fn write_back(&self) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let dirty: Vec<(usize, NonNull<Page>)> = self
+            .slots
+            .lock()
+            .iter_mut()
+            .filter(|(_, slot)| slot.dirty)
+            .filter_map(|(idx, slot)| {
+                slot.dirty = false;
+                slot.frame.map(|frame| (*idx, frame))
+            })
+            .collect();
+        // The frames stay alive while self holds them.
+        for (idx, frame) in dirty {
+            let data =
+                unsafe { core::slice::from_raw_parts(frame.as_ptr() as *const u8, PAGE_SIZE) };
+            if file.write_at(data, idx * PAGE_SIZE).is_err() {
+                log!("mmap: write back failed");
+            }
+        }
+    }
+}
+
+impl Drop for SharedPages {
+    fn drop(&mut self) {
+        for slot in self.slots.lock().values() {
+            if let Some(frame) = slot.frame {
+                kalloc::kfree(frame);
+            }
+        }
+    }
+}
+
+/// Allocate a page and fill it with the page of file at the offset,
+/// or with zeros if there is no file. Reading the file may sleep.
+fn new_page(file: Option<(&FileRef, usize)>) -> Option<NonNull<Page>> {
+    let mem = kalloc::kalloc()?;
+    let dst = unsafe { core::slice::from_raw_parts_mut(mem.as_ptr() as *mut u8, PAGE_SIZE) };
+    dst.iter_mut().for_each(|b| *b = 0);
+    if let Some((file, off)) = file {
+        // Past the end of the file reads as zeros.
+        let _ = file.read_at(dst, off);
+    }
+    Some(mem)
+}
+
+/// Describes a new mapping for `map()`.
+pub struct MapRequest {
+    pub addr: usize,
+    pub len: usize,
+    pub prot: u32,
+    pub flags: u32,
+    /// The file and the offset in it, unless MAP_ANONYMOUS
+    pub file: Option<(FileRef, usize)>,
+}
+
+/// Find room for len bytes among the (sorted) areas.
+fn find_free(vmas: &[Vma], len: usize) -> Option<usize> {
+    let mut start = MMAP_BASE;
+    for vma in vmas {
+        if vma.start >= start + len {
+            break;
+        }
+        start = usize::max(start, vma.end);
+    }
+    if start.checked_add(len)? <= MMAP_TOP {
+        Some(start)
+    } else {
+        None
+    }
+}
+
+fn is_free(vmas: &[Vma], start: usize, end: usize) -> bool {
+    MMAP_BASE <= start && end <= MMAP_TOP && vmas.iter().all(|v| v.end <= start || end <= v.start)
+}
+
+/// Add a mapping to vmas. The pages are not mapped until touched.
+/// Returns the start address, or None if the request is invalid
+/// or there is no room.
+pub fn map(vmas: &mut Vec<Vma>, req: MapRequest) -> Option<usize> {
+    let len = req.len.checked_add(PAGE_SIZE - 1)? / PAGE_SIZE * PAGE_SIZE;
+    if len == 0 || req.prot & !PROT_MASK != 0 {
+        return None;
+    }
+    let shared = match req.flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return None,
+    };
+    if let Some((_, off)) = req.file {
+        if off % PAGE_SIZE != 0 {
+            return None;
+        }
+    }
+
+    let start = if req.flags & MAP_FIXED != 0 {
+        let end = req.addr.checked_add(len)?;
+        if req.addr % PAGE_SIZE != 0 || !is_free(vmas, req.addr, end) {
+            return None;
+        }
+        req.addr
+    } else {
+        find_free(vmas, len)?
+    };
+
+    let (off, backing) = match (shared, req.file) {
+        (true, Some((file, off))) => (off, Backing::Shared(SharedPages::of_file(file))),
+        (true, None) => (0, Backing::Shared(Arc::new(SharedPages::new(None)))),
+        (false, Some((file, off))) => (off, Backing::File(file)),
+        (false, None) => (0, Backing::Anonymous),
+    };
+    let vma = Vma {
+        kind: VmaKind::Mapped,
+        start,
+        end: start + len,
+        prot: req.prot,
+        off,
+        backing,
+    };
+    insert(vmas, vma);
+    Some(start)
+}
+
+/// Insert vma into the sorted vmas.
+pub fn insert(vmas: &mut Vec<Vma>, vma: Vma) {
+    let pos = vmas
+        .iter()
+        .position(|v| v.start > vma.start)
+        .unwrap_or(vmas.len());
+    vmas.insert(pos, vma);
+}
+
+/// Remove [start, end) from vmas and unmap its pages from pg_dir,
+/// splitting areas that straddle the range.
+/// Returns the removed areas. Pass them to `release()` once no
+/// spin lock is held, since writing them back may sleep.
+pub fn unmap(
+    pg_dir: &mut PageDirectory,
+    vmas: &mut Vec<Vma>,
+    start: usize,
+    end: usize,
+) -> Vec<Vma> {
+    let mut kept = Vec::new();
+    let mut removed = Vec::new();
+    for vma in vmas.drain(..) {
+        if vma.end <= start || end <= vma.start {
+            kept.push(vma);
+            continue;
+        }
+        if vma.start < start {
+            kept.push(vma.slice(vma.start, start));
+        }
+        if end < vma.end {
+            kept.push(vma.slice(end, vma.end));
+        }
+        let gone = vma.slice(start, end);
+        if let Backing::Shared(pages) = &gone.backing {
+            for va in (gone.start..gone.end).step_by(PAGE_SIZE) {
+                if uvm::is_dirty(pg_dir, va) {
+                    pages.mark_dirty((gone.off + va - gone.start) / PAGE_SIZE);
+                }
+            }
+        }
+        uvm::dealloc(pg_dir, gone.end, gone.start);
+        removed.push(gone);
+    }
+    *vmas = kept;
+    removed
+}
+
+/// Whether every area overlapping [start, end) was added by mmap.
+pub fn only_mapped(vmas: &[Vma], start: usize, end: usize) -> bool {
+    vmas.iter()
+        .filter(|v| start < v.end && v.start < end)
+        .all(|v| v.kind == VmaKind::Mapped)
+}
+
+/// Write back and drop areas removed by `unmap()`. May sleep.
+pub fn release(removed: Vec<Vma>) {
+    for vma in removed.iter() {
+        if let Backing::Shared(pages) = &vma.backing {
+            pages.write_back();
+        }
+    }
+}
+
+/// Why `protect()` refused a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtectError {
+    /// prot has bits outside PROT_MASK.
+    InvalidProt,
+    /// Part of the range is in no area.
+    Unmapped,
+}
+
+/// Set prot on [start, end), splitting areas that straddle the range,
+/// and update the permissions of the pages already present in pg_dir.
+/// Changes nothing if it fails.
+pub fn protect(
+    pg_dir: &mut PageDirectory,
+    vmas: &mut Vec<Vma>,
+    start: usize,
+    end: usize,
+    prot: u32,
+) -> Result<(), ProtectError> {
+    if prot & !PROT_MASK != 0 {
+        return Err(ProtectError::InvalidProt);
+    }
+    let mut covered = start;
+    for vma in vmas.iter().filter(|v| start < v.end && v.start < end) {
+        if vma.start > covered {
+            return Err(ProtectError::Unmapped);
+        }
+        covered = vma.end;
+    }
+    if covered < end {
+        return Err(ProtectError::Unmapped);
+    }
+
+    let mut kept = Vec::new();
+    for vma in vmas.drain(..) {
+        if vma.end <= start || end <= vma.start {
+            kept.push(vma);
+            continue;
+        }
+        if vma.start < start {
+            kept.push(vma.slice(vma.start, start));
+        }
+        if end < vma.end {
+            kept.push(vma.slice(end, vma.end));
+        }
+        let mut changed = vma.slice(start, end);
+        changed.prot = prot;
+        uvm::protect(pg_dir, changed.start, changed.end, changed.perm());
+        kept.push(changed);
+    }
+    kept.sort_by_key(|v| v.start);
+    *vmas = kept;
+    Ok(())
+}
+
+/// Create a copy of pg_dir for a child, mapping the present pages of vmas
+/// to the same frames. Private pages become copy-on-write; shared pages stay shared.
+pub fn copy(pg_dir: &mut PageDirectory, vmas: &[Vma]) -> Option<Box<PageDirectory>> {
+    let mut new = vm::setup_kvm()?;
+    for vma in vmas {
+        if uvm::copy_range(pg_dir, &mut new, vma.start, vma.end, vma.is_shared()).is_none() {
+            vm::free_vm(new);
+            return None;
+        }
+    }
+    Some(new)
+}
+
+/// Handle a write to the present page containing va of p,
+/// which is copy-on-write unless the area forbids writing.
+pub fn copy_on_write(p: &mut Process, va: usize) -> Result<(), Fault> {
+    let vma = p
+        .vmas
+        .iter()
+        .find(|v| v.contains(va))
+        .ok_or(Fault::Invalid)?;
+    if vma.prot & PROT_WRITE == 0 || !uvm::is_cow(&mut p.pg_dir, va) {
+        return Err(Fault::Invalid);
+    }
+    // Copying a copy-on-write page only fails for lack of memory.
+    uvm::copy_on_write(&mut p.pg_dir, va).ok_or(Fault::NoMemory)
+}
+
+/// Why a page fault could not be resolved
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The address is in no area, or the area does not allow the access.
+    Invalid,
+    /// The stack would grow beyond STACK_LIMIT.
+    StackOverflow,
+    /// Out of memory
+    NoMemory,
+}
+
+/// Allocate the first page of the user stack in pg_dir, and add the stack area
+/// and the guard page beneath it to vmas. Returns the initial stack pointer.
+pub fn setup_stack(pg_dir: &mut PageDirectory, vmas: &mut Vec<Vma>) -> Option<usize> {
+    let bottom = STACK_TOP - PAGE_SIZE;
+    uvm::alloc(pg_dir, bottom, STACK_TOP, ent_flag::WRITABLE)?;
+    let rw = PROT_READ | PROT_WRITE;
+    insert(
+        vmas,
+        Vma::anonymous(VmaKind::Guard, bottom - PAGE_SIZE, bottom, PROT_NONE),
+    );
+    insert(vmas, Vma::anonymous(VmaKind::Stack, bottom, STACK_TOP, rw));
+    Some(STACK_TOP)
+}
+
+/// If va is beneath the stack area of p but within STACK_LIMIT of STACK_TOP,
+/// extend the area down to the page containing va and move the guard page beneath it.
+fn grow_stack(p: &mut Process, va: usize) -> Result<(), Fault> {
+    let limit = STACK_TOP - STACK_LIMIT;
+    if va >= STACK_TOP || va < limit - PAGE_SIZE {
+        return Ok(());
+    }
+    if va < limit {
+        // The guard page beneath the largest stack
+        return Err(Fault::StackOverflow);
+    }
+    let va = va - va % PAGE_SIZE;
+    let stack = match p.vmas.iter_mut().find(|v| v.kind == VmaKind::Stack) {
+        Some(stack) if va < stack.start => stack,
+        _ => return Ok(()),
+    };
+    stack.start = va;
+    if let Some(guard) = p.vmas.iter_mut().find(|v| v.kind == VmaKind::Guard) {
+        guard.start = va - PAGE_SIZE;
+        guard.end = va;
+    }
+    Ok(())
+}
+
+/// Page in the page containing va of an area of p,
+/// growing the stack if va is beneath it.
+/// May sleep, so p must not be locked by the caller.
+pub fn page_in(p: &ProcessRef, va: usize, write: bool) -> Result<(), Fault> {
+    let va = va - va % PAGE_SIZE;
+    let vma = {
+        let mut p = p.lock();
+        grow_stack(&mut p, va)?;
+        let vma = p
+            .vmas
+            .iter()
+            .find(|v| v.contains(va))
+            .ok_or(Fault::Invalid)?;
+        let allowed = if write {
+            vma.prot & PROT_WRITE != 0
+        } else {
+            vma.perm() & ent_flag::USER != 0
+        };
+        if !allowed {
+            return Err(Fault::Invalid);
+        }
+        vma.slice(va, va + PAGE_SIZE)
+    };
+
+    let page = match &vma.backing {
+        Backing::Anonymous => new_page(None),
+        Backing::File(file) => new_page(Some((file, vma.off))),
+        Backing::Shared(pages) => pages.get(vma.off / PAGE_SIZE),
+    }
+    .ok_or(Fault::NoMemory)?;
+    let mapped = {
+        let mut p = p.lock();
+        uvm::map_page(&mut p.pg_dir, va, page, vma.perm())
+    };
+    if mapped.is_none() {
+        kalloc::kfree(page);
+    }
+    // Drop the references to the backing object without the lock.
+    drop(vma);
+    mapped.ok_or(Fault::NoMemory)
+}
+
+/// Page in the pages of [start, end) of p that are not present yet and,
+/// if write is true, copy those that are copy-on-write,
+/// so that the kernel can access them without faulting.
+/// May sleep, so p must not be locked by the caller.
+pub fn fault_in(p: &ProcessRef, start: usize, end: usize, write: bool) -> Result<(), Fault> {
+    let start = start - start % PAGE_SIZE;
+    for va in (start..end).step_by(PAGE_SIZE) {
+        let present = {
+            let mut p = p.lock();
+            let present = uvm::is_present(&mut p.pg_dir, va);
+            if present && write && uvm::is_cow(&mut p.pg_dir, va) {
+                copy_on_write(&mut p, va)?;
+            }
+            present
+        };
+        if !present {
+            page_in(p, va, write)?;
+        }
+    }
+    Ok(())
+}
+
+/// End of the accessible memory of vmas that extends contiguously from va,
+/// or None if va itself is not accessible.
+/// If write is true, only writable memory counts as accessible.
+pub fn accessible_end(vmas: &[Vma], va: usize, write: bool) -> Option<usize> {
+    let accessible = |v: &&Vma| match write {
+        true => v.prot & PROT_WRITE != 0,
+        false => v.perm() & ent_flag::USER != 0,
+    };
+    let mut end = None;
+    for vma in vmas.iter().filter(accessible) {
+        match end {
+            None if vma.contains(va) => end = Some(vma.end),
+            Some(e) if vma.start == e => end = Some(vma.end),
+            Some(_) => break,
+            None => {}
+        }
+    }
+    end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RW: u32 = PROT_READ | PROT_WRITE;
+
+    fn ranges(vmas: &[Vma]) -> Vec<(usize, usize, u32)> {
+        vmas.iter().map(|v| (v.start, v.end, v.prot)).collect()
+    }
+
+    fn area(start: usize, pages: usize, prot: u32) -> Vma {
+        let start = MMAP_BASE + start * PAGE_SIZE;
+        Vma::anonymous(VmaKind::Mapped, start, start + pages * PAGE_SIZE, prot)
+    }
+
+    #[test_case]
+    fn test_unmap_split() {
+        let mut pg_dir = PageDirectory::zero_boxed();
+        let mut vmas = alloc::vec![area(0, 4, RW)];
+        let (a, p) = (MMAP_BASE, PAGE_SIZE);
+
+        let removed = unmap(&mut pg_dir, &mut vmas, a + p, a + 3 * p);
+        assert_eq!(ranges(&vmas), [(a, a + p, RW), (a + 3 * p, a + 4 * p, RW)]);
+        assert_eq!(ranges(&removed), [(a + p, a + 3 * p, RW)]);
+
+        // Unmapping across the hole removes the rest of both areas.
+        let removed = unmap(&mut pg_dir, &mut vmas, a, a + 4 * p);
+        assert!(vmas.is_empty());
+        assert_eq!(removed.len(), 2);
+    }
+
+    #[test_case]
+    fn test_protect_split() {
+        let mut pg_dir = PageDirectory::zero_boxed();
+        let mut vmas = alloc::vec![area(0, 4, RW), area(4, 2, PROT_READ)];
+        let (a, p) = (MMAP_BASE, PAGE_SIZE);
+
+        assert!(protect(&mut pg_dir, &mut vmas, a + p, a + 2 * p, PROT_READ).is_ok());
+        assert_eq!(
+            ranges(&vmas),
+            [
+                (a, a + p, RW),
+                (a + p, a + 2 * p, PROT_READ),
+                (a + 2 * p, a + 4 * p, RW),
+                (a + 4 * p, a + 6 * p, PROT_READ),
+            ]
+        );
+
+        // A range spanning several areas is changed in each of them.
+        assert!(protect(&mut pg_dir, &mut vmas, a + 3 * p, a + 5 * p, PROT_NONE).is_ok());
+        assert_eq!(
+            ranges(&vmas),
+            [
+                (a, a + p, RW),
+                (a + p, a + 2 * p, PROT_READ),
+                (a + 2 * p, a + 3 * p, RW),
+                (a + 3 * p, a + 4 * p, PROT_NONE),
+                (a + 4 * p, a + 5 * p, PROT_NONE),
+                (a + 5 * p, a + 6 * p, PROT_READ),
+            ]
+        );
+
+        // A range not entirely covered changes nothing.
+        let before = ranges(&vmas);
+        assert_eq!(
+            protect(&mut pg_dir, &mut vmas, a + 5 * p, a + 7 * p, RW),
+            Err(ProtectError::Unmapped)
+        );
+        assert_eq!(
+            protect(&mut pg_dir, &mut vmas, a, a + p, 0x8),
+            Err(ProtectError::InvalidProt)
+        );
+        assert_eq!(ranges(&vmas), before);
+    }
+
+    #[test_case]
+    fn test_page_in_no_memory() {
+        let p = Arc::new(SpinMutex::new("test", crate::proc::Process::new()));
+        let va = MMAP_BASE;
+        p.lock().vmas.push(area(0, 1, RW));
+
+        // Use up all pages, chaining them through their first word.
+        let mut last: *mut usize = core::ptr::null_mut();
+        while let Some(page) = kalloc::kalloc() {
+            let page = page.as_ptr() as *mut usize;
+            unsafe { *page = last as usize };
+            last = page;
+        }
+        assert_eq!(page_in(&p, va, true), Err(Fault::NoMemory));
+        assert_eq!(fault_in(&p, va, va + 1, false), Err(Fault::NoMemory));
+        while let Some(page) = NonNull::new(last) {
+            last = unsafe { *page.as_ptr() } as *mut usize;
+            kalloc::kfree(page.cast());
+        }
+
+        assert_eq!(fault_in(&p, va, va + 1, true), Ok(()));
+        assert!(uvm::is_present(&mut p.lock().pg_dir, va));
+        let pg_dir = core::mem::replace(&mut p.lock().pg_dir, PageDirectory::zero_boxed());
+        vm::free_vm(pg_dir);
+    }
+
+    #[test_case]
+    fn test_accessible_end() {
+        let vmas = alloc::vec![
+            area(0, 1, RW),
+            area(1, 1, PROT_READ),
+            area(2, 1, PROT_NONE),
+            area(4, 1, RW),
+        ];
+        let (a, p) = (MMAP_BASE, PAGE_SIZE);
+
+        // Adjacent accessible areas count as one.
+        assert_eq!(accessible_end(&vmas, a, false), Some(a + 2 * p));
+        assert_eq!(accessible_end(&vmas, a + p + 8, false), Some(a + 2 * p));
+        // Only writable areas count for writing.
+        assert_eq!(accessible_end(&vmas, a, true), Some(a + p));
+        assert_eq!(accessible_end(&vmas, a + p, true), None);
+        // Neither PROT_NONE areas nor holes are accessible.
+        assert_eq!(accessible_end(&vmas, a + 2 * p, false), None);
+        assert_eq!(accessible_end(&vmas, a + 3 * p, false), None);
+        assert_eq!(accessible_end(&vmas, a + 4 * p, true), Some(a + 5 * p));
+    }
+}
@@ -187,6 +187,7 @@ fn main() -> Result<()> {
 
     let mut builder = {
         let mut sb = SuperBlock::default();
+        sb.magic = FS_MAGIC.to_le();
         sb.size = (FS_SIZE as u32).to_le();
         sb.n_blocks = (N_BLOCKS as u32).to_le();
         sb.n_inodes = (N_INODES as u32).to_le();
@@ -197,6 +198,7 @@ fn main() -> Result<()> {
         FsBuilder::create(&args[1], sb)?
     };
 
+    assert!(builder.super_block.is_consistent());
     println!("nmeta {} (boot, super, log blocks {}, inode blocks {}, bitmap blocks {}) blocks {} total {}", N_META, N_LOG, N_INODE_BLOCKS, N_BITMAP, N_BLOCKS, FS_SIZE);
     assert_eq!(N_META + N_BLOCKS, FS_SIZE);
 
@@ -1,3 +1,5 @@
+//! Format of an ELF executable file
+
 /// ELF32 header
 #[repr(C)]
 pub struct ElfHeader {
@@ -10,11 +12,11 @@ pub struct ElfHeader {
     /// object file version
     pub e_version: u32,
     /// virtual entry point
-    pub e_entry: extern "C" fn() -> (),
+    pub e_entry: u32,
     /// program header table offset
-    pub e_phoff: usize,
+    pub e_phoff: u32,
     /// section header table offset
-    pub e_shoff: usize,
+    pub e_shoff: u32,
     /// processor-specific flags
     pub e_flags: u32,
     /// ELF header size
@@ -37,30 +39,40 @@ pub struct ProgHeader {
     /// segment type
     pub p_type: u32,
     /// segment offset
-    pub p_offset: usize,
+    pub p_offset: u32,
     /// virtual address of segment
-    pub p_vaddr: *mut u8,
+    pub p_vaddr: u32,
     /// physical address - ignored ?
-    pub p_paddr: *mut u8,
+    pub p_paddr: u32,
     /// number of bytes in file for seg.
-    pub p_filesz: usize,
+    pub p_filesz: u32,
     /// number of bytes in mem. for seg.
-    pub p_memsz: usize,
+    pub p_memsz: u32,
     /// flags
     pub p_flags: u32,
     /// memory alignment
-    pub p_align: usize,
+    pub p_align: u32,
 }
 
 const ELF_MAGIC: [u8; 4] = [0x7F, 0x45, 0x4C, 0x46]; // 0x7F, 'E', 'L', 'F'
 
+/// Values for ProgHeader::p_type
+pub const ELF_PROG_LOAD: u32 = 1;
+
+/// Flag bits for ProgHeader::p_flags
+pub const ELF_PROG_FLAG_EXEC: u32 = 1;
+pub const ELF_PROG_FLAG_WRITE: u32 = 2;
+pub const ELF_PROG_FLAG_READ: u32 = 4;
+
 impl ElfHeader {
     pub fn verify(&self) -> bool {
         self.e_ident[..4] == ELF_MAGIC
     }
+    /// Program headers following this header in memory.
+    /// The whole table must be loaded right after the header (e.g. the first page of the file).
     pub fn prog_headers(&self) -> &[ProgHeader] {
         let self_ptr = self as *const _ as *mut u8;
-        let prg_hdr = unsafe { self_ptr.add(self.e_phoff) } as *mut ProgHeader;
+        let prg_hdr = unsafe { self_ptr.add(self.e_phoff as usize) } as *mut ProgHeader;
         unsafe { core::slice::from_raw_parts(prg_hdr, self.e_phnum as usize) }
     }
 }
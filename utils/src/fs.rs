@@ -0,0 +1,126 @@
+//! On-disk file system format.
+//! Both the kernel and mkfs use this definition.
+//! All multi-byte fields are stored in little endian.
+
+use core::mem::size_of;
+
+pub type InodeNum = u16;
+
+/// root i-number
+pub const ROOT_INO: InodeNum = 1;
+/// block size
+pub const BLK_SIZE: usize = 512;
+
+/// Magic number stored in SuperBlock::magic
+pub const FS_MAGIC: u32 = 0x1020_3040;
+
+pub const N_DIRECT: usize = 12;
+pub const N_INDIRECT: usize = BLK_SIZE / size_of::<u32>();
+pub const MAX_FILE: usize = N_DIRECT + N_INDIRECT;
+
+/// Max # of blocks any FS op writes
+pub const MAX_OP_BLOCKS: usize = 10;
+/// Max data blocks in on-disk log
+pub const LOG_SIZE: usize = MAX_OP_BLOCKS * 3;
+
+/// Inodes per block
+pub const INODES_PER_BLOCK: usize = BLK_SIZE / size_of::<OnDiskInode>();
+/// Bitmap bits per block
+pub const BITS_PER_BLOCK: usize = BLK_SIZE * 8;
+
+/// Disk layout:
+/// [ boot block | super block | log | inode blocks | free bit map | data blocks ]
+#[derive(Default, Clone, Debug)]
+#[repr(C)]
+pub struct SuperBlock {
+    /// Must be FS_MAGIC
+    pub magic: u32,
+    /// Size of file system image (blocks)
+    pub size: u32,
+    /// Number of data blocks
+    pub n_blocks: u32,
+    /// Number of inodes
+    pub n_inodes: u32,
+    /// Number of log blocks
+    pub n_log: u32,
+    /// Block number of first log block
+    pub log_start: u32,
+    /// Block number of first inode block
+    pub inode_start: u32,
+    /// Block number of first free map block
+    pub bmap_start: u32,
+}
+impl SuperBlock {
+    /// Block containing inode i
+    pub fn inode_block(&self, i: u32) -> u32 {
+        i / INODES_PER_BLOCK as u32 + self.inode_start
+    }
+    /// Block of free map containing bit for block b
+    pub fn bitmap_block(&self, b: u32) -> u32 {
+        b / BITS_PER_BLOCK as u32 + self.bmap_start
+    }
+    /// Check that the regions of the layout follow each other
+    /// and that they fit in a device of `size` blocks.
+    pub fn is_consistent(&self) -> bool {
+        let n_inode_blocks = self.n_inodes / INODES_PER_BLOCK as u32 + 1;
+        let n_bitmap = self.size / BITS_PER_BLOCK as u32 + 1;
+        let n_meta = self.bmap_start as u64 + n_bitmap as u64;
+        self.magic == FS_MAGIC
+            && self.log_start == 2
+            && self.inode_start as u64 == self.log_start as u64 + self.n_log as u64
+            && self.bmap_start as u64 >= self.inode_start as u64 + n_inode_blocks as u64
+            && n_meta + self.n_blocks as u64 <= self.size as u64
+    }
+}
+
+#[derive(Default, Clone)]
+#[repr(C)]
+pub struct OnDiskInode {
+    /// File type
+    pub type_: u16,
+    /// Major device number
+    pub major: u16,
+    /// Minor device number
+    pub minor: u16,
+    /// Number of links to inode in file system
+    pub n_link: u16,
+    /// Size of file (bytes)
+    pub size: u32,
+    /// Data block addresses
+    pub addrs: [u32; N_DIRECT + 1],
+}
+
+/// Directory is a file containing a sequence of DirEnt structures.
+pub const DIR_SIZE: usize = 14;
+
+#[derive(Default, Clone)]
+#[repr(C)]
+pub struct DirEnt {
+    pub inum: InodeNum,
+    pub name: [u8; DIR_SIZE],
+}
+impl DirEnt {
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, size_of::<Self>()) }
+    }
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self as *mut _ as *mut u8, size_of::<Self>()) }
+    }
+    /// The name without trailing nul bytes.
+    pub fn name(&self) -> &[u8] {
+        let len = self.name.iter().position(|c| *c == 0).unwrap_or(DIR_SIZE);
+        &self.name[..len]
+    }
+    /// Does the entry hold name?
+    /// Only the first DIR_SIZE bytes are compared, as set_name keeps no more.
+    pub fn has_name(&self, name: &[u8]) -> bool {
+        self.name() == &name[..usize::min(name.len(), DIR_SIZE)]
+    }
+    /// Set the name, truncating it to DIR_SIZE bytes.
+    pub fn set_name(&mut self, name: &[u8]) {
+        assert!(!name.contains(&b'/'));
+        let len = usize::min(name.len(), DIR_SIZE);
+        self.name = [0; DIR_SIZE];
+        self.name[..len].copy_from_slice(&name[..len]);
+    }
+}
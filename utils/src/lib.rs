@@ -5,6 +5,8 @@
 #![allow(clippy::identity_op)]
 
 pub mod address;
+pub mod elf;
+pub mod fs;
 pub mod x86;
 
 /// Imitate C99's designated initializer
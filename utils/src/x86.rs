@@ -126,6 +126,21 @@ pub fn read_eflags() -> u32 {
     eflags
 }
 
+/// Read the time-stamp counter
+#[inline]
+pub fn rdtsc() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        llvm_asm!("rdtsc"
+            : "={eax}"(lo), "={edx}"(hi)
+            :
+            :
+            : "volatile");
+    }
+    (hi as u64) << 32 | lo as u64
+}
+
 #[inline]
 pub fn cli() {
     unsafe {
@@ -162,6 +177,32 @@ pub fn lcr3(val: u32) {
     }
 }
 
+/// Return the linear address that caused the last page fault
+#[inline]
+pub fn rcr2() -> u32 {
+    let val;
+    unsafe {
+        llvm_asm!("movl %cr2, $0"
+            : "=r"(val)
+            :
+            :
+            : "volatile");
+    }
+    val
+}
+
+/// Invalidate the TLB entry of the page containing addr
+#[inline]
+pub fn invlpg(addr: usize) {
+    unsafe {
+        llvm_asm!("invlpg ($0)"
+            :
+            : "r"(addr)
+            : "memory"
+            : "volatile");
+    }
+}
+
 #[inline]
 pub fn lgdt(seg_desc: *const u8, sz: u16) {
     let pd: [u16; 3] = [